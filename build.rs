@@ -0,0 +1,16 @@
+//! Picks the memory layout, diagnostics builds set aside the top 1K of RAM for the panic message.
+//! The chosen file is copied to OUT_DIR as memory.x, where the cortex-m-rt linker script finds it.
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let layout = if env::var_os("CARGO_FEATURE_DIAGNOSTICS").is_some() {
+        "memory/diagnostics.x"
+    } else {
+        "memory/default.x"
+    };
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy(layout, out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed={}", layout);
+    println!("cargo:rerun-if-changed=build.rs");
+}
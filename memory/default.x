@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* Flash memory begins at 0x80000000 and has a size of 64kB, the last 1kB page holds the saved
+     settings */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 63K
+  /* RAM begins at 0x20000000 and has a size of 20kB */
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+}
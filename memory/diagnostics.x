@@ -0,0 +1,12 @@
+MEMORY
+{
+  /* Flash memory begins at 0x80000000 and has a size of 64kB, the last 1kB page holds the saved
+     settings */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 63K
+  /* RAM begins at 0x20000000 and has a size of 20kB, the last 1kB holds the panic message */
+  RAM : ORIGIN = 0x20000000, LENGTH = 19K
+  PANDUMP : ORIGIN = 0x20004C00, LENGTH = 1K
+}
+
+_panic_dump_start = ORIGIN(PANDUMP);
+_panic_dump_end = ORIGIN(PANDUMP) + LENGTH(PANDUMP);
@@ -1,35 +1,293 @@
+use crate::op_buf::WRITE_N_HEADER_SIZE;
+use core::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+};
 use snafu::Snafu;
 
 pub const I_FACE_VERSION: u16 = 0x01;
-pub const PGM_NAME: &str = "stm32-vserprog";
+// Length of the Q_PGMNAME reply and of the Q_CMDMAP bitmap, one bit per opcode
+pub const PGM_NAME_SIZE: usize = 16;
+pub const CMD_MAP_SIZE: usize = 32;
+// Short enough to leave room for PGM_VERSION in the 16 byte Q_PGMNAME reply
+pub const PGM_NAME: &str = "stm32-serprog";
+// Appended to PGM_NAME so hosts can tell firmware builds apart
+pub const PGM_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    ".",
+    env!("CARGO_PKG_VERSION_MINOR")
+);
 // Support SPI only
-pub const SUPPORTED_BUS: u8 = 1 << 3;
-pub const CMD_MAP: u32 = 1 << OpCode::Nop as u8
-    | 1 << OpCode::QIface as u8
-    | 1 << OpCode::QCmdMap as u8
-    | 1 << OpCode::QPgmName as u8
-    | 1 << OpCode::QSerBuf as u8
-    | 1 << OpCode::QBusType as u8
-    | 1 << OpCode::SyncNop as u8
-    | 1 << OpCode::OSpiOp as u8
-    | 1 << OpCode::SBusType as u8
-    | 1 << OpCode::SSpiFreq as u8
-    | 1 << OpCode::SPinState as u8;
+pub const SUPPORTED_BUS: BusType = BusType::SPI;
+/// Opcodes with a handler in this build, advertised through Q_CMDMAP
+pub const SUPPORTED_OPCODES: &[OpCode] = &[
+    OpCode::Nop,
+    OpCode::QIface,
+    OpCode::QCmdMap,
+    OpCode::QPgmName,
+    OpCode::QSerBuf,
+    OpCode::QBusType,
+    OpCode::QChipSize,
+    OpCode::QOpBuf,
+    OpCode::QWrnMaxLen,
+    #[cfg(feature = "memory-read")]
+    OpCode::RByte,
+    #[cfg(feature = "memory-read")]
+    OpCode::RNBytes,
+    OpCode::OInit,
+    OpCode::OWriteB,
+    OpCode::OWriteN,
+    OpCode::ODelay,
+    OpCode::OExec,
+    OpCode::SyncNop,
+    OpCode::QRdnMaxLen,
+    OpCode::SBusType,
+    OpCode::OSpiOp,
+    OpCode::SSpiFreq,
+    OpCode::SPinState,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VProbe,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VQSpiConfig,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VVerifyCrc,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VMultiRead,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSResponseDelay,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSCrc8Mode,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VFactoryReset,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VReadSfdp,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSSpiMode,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSChipSize,
+    #[cfg(feature = "bootloader-reset")]
+    OpCode::VBootloader,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSCsHighTime,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VLoopback,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VQCapabilities,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSChipSelect,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VQDiagnostics,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VAbort,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSPinSpeed,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSCsSetupTime,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSaveSettings,
+    #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+    OpCode::VSAddressEndian,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VReadJedecId,
+    #[cfg(feature = "diagnostics")]
+    OpCode::VReadPanic,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSFrameSize,
+    #[cfg(feature = "vendor-extension")]
+    OpCode::VSReadRetries,
+];
 pub const MAX_BUFFER_SIZE: usize = 128;
+// Commands are consumed from the USB endpoint as they arrive and the endpoint NAKs the host while
+// a command is being handled, so no amount of queued commands can overflow. Any size is truthful,
+// every buffer size reported to the host derives from this one.
+pub const SER_BUF_SIZE: usize = 64 * 1024;
+// Longest gap between two bytes of the same command before it is dropped
+pub const COMMAND_TIMEOUT_US: u32 = 250_000;
+// Longest the host may leave the IN endpoint full before a response is abandoned
+pub const RESPONSE_TIMEOUT_US: u32 = 250_000;
+// Room for the ops queued until O_EXEC
+pub const OP_BUF_SIZE: usize = 4 * 1024;
+// Longest O_WRITEN that is queued, it has to fit in the operation buffer along with its header.
+// This is what Q_WRNMAXLEN advertises.
+pub const MAX_WRITE_N: usize = OP_BUF_SIZE - WRITE_N_HEADER_SIZE;
+// Longest O_WRITEN accepted at all, longer than MAX_WRITE_N they are programmed as they arrive
+pub const MAX_WRITE_N_STREAMED: usize = 0xFF_FFFF;
+// Longest read, they are streamed so only the 24 bit length field bounds them
+pub const MAX_READ_N: usize = 0xFF_FFFF;
+// Longest O_SPIOP write phase, the whole command has to fit in the serial buffer. The opcode and
+// both 24 bit lengths come first.
+pub const MAX_SPI_OP_WRITE: usize = SER_BUF_SIZE - 7;
+// Longest a page program may take, datasheets give a few ms
+pub const PROGRAM_TIMEOUT_US: u32 = 20_000;
+// Resets the MCU if the firmware wedges, everything that waits feeds it
+pub const WATCHDOG_TIMEOUT_MS: u32 = 2_000;
+// How long the LED stays lit after a command, so that short ones are visible
+pub const LED_FLASH_US: u32 = 50_000;
+// Delay before each response, some hosts misbehave when the reply comes too quickly
+pub const RESPONSE_DELAY_US: u32 = 0;
+// Longest response delay the host may set, the busy wait has to stay well inside the watchdog
+// timeout
+#[cfg(feature = "vendor-extension")]
+pub const MAX_RESPONSE_DELAY_US: u32 = 500_000;
+#[cfg(feature = "vendor-extension")]
+const _: () = assert!(MAX_RESPONSE_DELAY_US / 1_000 < WATCHDOG_TIMEOUT_MS / 2);
+// Backup data register that survives the reset into the ROM bootloader, and the value asking for it
+#[cfg(feature = "bootloader-reset")]
+pub const BOOTLOADER_MAGIC_REGISTER: usize = 0;
+#[cfg(feature = "bootloader-reset")]
+pub const BOOTLOADER_MAGIC: u16 = 0xB007;
+// Bounds of a batched multi-region read
+#[cfg(feature = "vendor-extension")]
+pub const MAX_READ_REGIONS: usize = 16;
 
 #[derive(Snafu, Debug)]
 pub enum DataError {
     // #[snafu(display("Buffer of size {} provided while a buffer of size {} was required", buf_size, required))]
-    BufferTooSmall { buf_size: usize, required: usize },
+    BufferTooSmall {
+        buf_size: usize,
+        required: usize,
+    },
+    #[snafu(display("Byte {:#04x} is neither an Ack nor a Nak", byte))]
+    InvalidResponseType {
+        byte: u8,
+    },
 }
 
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ResponseType {
     Ack = 0x06,
     Nak = 0x15,
 }
 
+impl TryFrom<u8> for ResponseType {
+    type Error = DataError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0x06 => Ok(ResponseType::Ack),
+            0x15 => Ok(ResponseType::Nak),
+            byte => Err(DataError::InvalidResponseType { byte }),
+        }
+    }
+}
+
+/// What this build of the firmware can do, for hosts that would rather not guess from PGM_NAME
+#[cfg(feature = "vendor-extension")]
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    /// Bit n set if SPI mode n is supported
+    pub spi_modes: u8,
+    /// Data line configurations, bit 0 single, bit 1 dual and bit 2 quad
+    pub io_modes: u8,
+    /// CS is driven by the SPI peripheral rather than a GPIO
+    pub hardware_cs: bool,
+    pub max_freq: u32,
+    /// Major, minor and patch
+    pub version: [u8; 3],
+}
+
+#[cfg(feature = "vendor-extension")]
+impl Capabilities {
+    pub const SIZE: usize = 10;
+
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0] = self.spi_modes;
+        bytes[1] = self.io_modes;
+        bytes[2] = self.hardware_cs as u8;
+        bytes[3..7].copy_from_slice(&self.max_freq.to_le_bytes());
+        bytes[7..].copy_from_slice(&self.version);
+        bytes
+    }
+}
+
+/// Crate version as major, minor and patch
+#[cfg(feature = "vendor-extension")]
+pub fn firmware_version() -> [u8; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH"),
+    ]
+    .map(|part| part.parse().unwrap_or(u8::MAX))
+}
+
+/// Byte order of the 24 bit addresses in R_BYTE and R_NBYTES. The spec has them little endian,
+/// some masters fronted by the dongle send them big endian.
+#[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressEndian {
+    Little,
+    Big,
+}
+
+/// Bitmask of bus types as Q_BUSTYPE and S_BUSTYPE encode them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusType(u8);
+
+impl BusType {
+    pub const PARALLEL: BusType = BusType(1 << 0);
+    pub const LPC: BusType = BusType(1 << 1);
+    pub const FWH: BusType = BusType(1 << 2);
+    pub const SPI: BusType = BusType(1 << 3);
+    // Bits the protocol doesn't assign to any bus
+    const RESERVED: u8 = !0x0F;
+
+    /// None if any reserved bit is set
+    pub const fn new(bits: u8) -> Option<Self> {
+        if bits & Self::RESERVED != 0 {
+            None
+        } else {
+            Some(Self(bits))
+        }
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn parallel(self) -> bool {
+        self.0 & Self::PARALLEL.0 != 0
+    }
+
+    pub const fn lpc(self) -> bool {
+        self.0 & Self::LPC.0 != 0
+    }
+
+    pub const fn fwh(self) -> bool {
+        self.0 & Self::FWH.0 != 0
+    }
+
+    pub const fn spi(self) -> bool {
+        self.0 & Self::SPI.0 != 0
+    }
+}
+
+/// Set buses separated by `|`, such as `PARALLEL | SPI`, or `NONE`
+impl Display for BusType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let flags = [
+            (self.parallel(), "PARALLEL"),
+            (self.lpc(), "LPC"),
+            (self.fwh(), "FWH"),
+            (self.spi(), "SPI"),
+        ];
+        let mut first = true;
+        for (_, name) in flags.iter().filter(|(set, _)| *set) {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        if first {
+            f.write_str("NONE")?;
+        }
+        Ok(())
+    }
+}
+
 #[repr(C)]
 pub enum ResponsePacket {
     Nop,
@@ -37,10 +295,10 @@ pub enum ResponsePacket {
         iface_version: u16,
     },
     QCmdMap {
-        cmd_map: [u8; 32],
+        cmd_map: [u8; CMD_MAP_SIZE],
     },
     QPgmName {
-        pgm_name: [u8; 16],
+        pgm_name: [u8; PGM_NAME_SIZE],
     },
     QSerBuf {
         size: u16,
@@ -48,23 +306,93 @@ pub enum ResponsePacket {
     QBusType {
         bus_type: u8,
     },
-    SyncNop,
-    SBusType {
+    QChipSize {
         res: ResponseType,
+        size_log2: u8,
     },
-    SpiOp {
+    QOpBuf {
+        size: u16,
+    },
+    /// Only the low 24 bits go on the wire
+    QWrnMaxLen {
+        size: u32,
+    },
+    /// Only the low 24 bits go on the wire
+    QRdnMaxLen {
+        size: u32,
+    },
+    #[cfg(feature = "memory-read")]
+    RByte {
+        byte: u8,
+    },
+    /// Reply to the opbuf commands
+    OpBuf {
+        res: ResponseType,
+    },
+    SyncNop,
+    SBusType {
         res: ResponseType,
-        rlen: usize,
-        data: [u8; MAX_BUFFER_SIZE],
     },
     SSpiFreq {
         res: ResponseType,
         set_freq: u32,
     },
+    SPinState {
+        res: ResponseType,
+    },
+    #[cfg(feature = "vendor-extension")]
+    VQSpiConfig {
+        dummy_byte: u8,
+        io_modes: u8,
+        spi_modes: u8,
+        cs_active_high: bool,
+    },
+    #[cfg(feature = "vendor-extension")]
+    VVerifyCrc {
+        res: ResponseType,
+        crc: u32,
+    },
+    #[cfg(feature = "vendor-extension")]
+    VQCapabilities {
+        capabilities: Capabilities,
+    },
+    /// Manufacturer and device ID bytes, unchecked
+    #[cfg(feature = "vendor-extension")]
+    VJedecId {
+        id: [u8; 3],
+    },
+    /// Error counts since power on
+    #[cfg(feature = "vendor-extension")]
+    VQDiagnostics {
+        dropped_writes: u32,
+        parse_errors: u32,
+    },
+    /// Nak carries the offset of the first byte that didn't come back
+    #[cfg(feature = "vendor-extension")]
+    VLoopback {
+        res: ResponseType,
+        offset: u8,
+    },
+    /// The handler already wrote its response to the serial port
+    Streamed,
+    /// Reply to vendor setters
+    #[cfg(feature = "vendor-extension")]
+    VSet {
+        res: ResponseType,
+    },
 }
 
 impl ResponsePacket {
-    pub const MAX_SIZE: usize = 33;
+    /// Largest reply built in one piece, an Ack with the largest fixed payload. Replies carrying
+    /// flash data are streamed and never go through to_bytes().
+    pub const MAX_SIZE: usize = 1 + Self::MAX_PAYLOAD_SIZE;
+
+    const MAX_PAYLOAD_SIZE: usize = {
+        let size = larger(CMD_MAP_SIZE, PGM_NAME_SIZE);
+        #[cfg(feature = "vendor-extension")]
+        let size = larger(size, Capabilities::SIZE);
+        size
+    };
 
     pub fn to_bytes(&self, buf: &mut [u8]) -> Result<usize, DataError> {
         let packet_size = self.packet_size();
@@ -83,49 +411,128 @@ impl ResponsePacket {
             }
             ResponsePacket::QIface { iface_version } => {
                 buf[0] = ResponseType::Ack as u8;
-                buf[1..].copy_from_slice(&iface_version.to_le_bytes());
+                buf[1..3].copy_from_slice(&iface_version.to_le_bytes());
             }
             ResponsePacket::QCmdMap { cmd_map } => {
                 buf[0] = ResponseType::Ack as u8;
-                buf[1..].copy_from_slice(cmd_map);
+                buf[1..1 + CMD_MAP_SIZE].copy_from_slice(cmd_map);
             }
             ResponsePacket::QPgmName { pgm_name } => {
                 buf[0] = ResponseType::Ack as u8;
-                buf[1..].copy_from_slice(pgm_name);
+                buf[1..1 + PGM_NAME_SIZE].copy_from_slice(pgm_name);
             }
             ResponsePacket::QSerBuf { size } => {
                 buf[0] = ResponseType::Ack as u8;
-                buf[1..].copy_from_slice(&size.to_le_bytes());
+                buf[1..3].copy_from_slice(&size.to_le_bytes());
             }
             ResponsePacket::QBusType { bus_type } => {
                 buf[0] = ResponseType::Ack as u8;
                 buf[1] = *bus_type;
             }
-            ResponsePacket::SyncNop => {
+            ResponsePacket::QChipSize { res, size_log2 } => {
+                buf[0] = *res as u8;
+                match res {
+                    ResponseType::Nak => (),
+                    ResponseType::Ack => {
+                        buf[1] = *size_log2;
+                    }
+                }
+            }
+            ResponsePacket::QOpBuf { size } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1..3].copy_from_slice(&size.to_le_bytes());
+            }
+            ResponsePacket::QWrnMaxLen { size } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1..4].copy_from_slice(&size.to_le_bytes()[..3]);
+            }
+            ResponsePacket::QRdnMaxLen { size } => {
                 buf[0] = ResponseType::Ack as u8;
-                buf[1] = ResponseType::Nak as u8;
+                buf[1..4].copy_from_slice(&size.to_le_bytes()[..3]);
+            }
+            #[cfg(feature = "memory-read")]
+            ResponsePacket::RByte { byte } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1] = *byte;
+            }
+            ResponsePacket::OpBuf { res } => {
+                buf[0] = *res as u8;
+            }
+            // flashrom's sp_synchronize() waits for exactly this order
+            ResponsePacket::SyncNop => {
+                buf[0] = ResponseType::Nak as u8;
+                buf[1] = ResponseType::Ack as u8;
             }
             ResponsePacket::SBusType { res } => {
                 buf[0] = *res as u8;
             }
-            ResponsePacket::SpiOp { res, rlen, data } => {
+            ResponsePacket::SPinState { res } => {
+                buf[0] = *res as u8;
+            }
+            ResponsePacket::SSpiFreq { res, set_freq } => {
                 buf[0] = *res as u8;
                 match res {
                     ResponseType::Nak => (),
                     ResponseType::Ack => {
-                        buf[1..*rlen].copy_from_slice(&data[..*rlen]);
+                        buf[1..5].copy_from_slice(&set_freq.to_le_bytes());
                     }
                 }
             }
-            ResponsePacket::SSpiFreq { res, set_freq } => {
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VQSpiConfig {
+                dummy_byte,
+                io_modes,
+                spi_modes,
+                cs_active_high,
+            } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1] = *dummy_byte;
+                buf[2] = *io_modes;
+                buf[3] = *spi_modes;
+                buf[4] = *cs_active_high as u8;
+            }
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VVerifyCrc { res, crc } => {
                 buf[0] = *res as u8;
                 match res {
-                    ResponseType::Nak => (),
-                    ResponseType::Ack => {
-                        buf[1..].copy_from_slice(&set_freq.to_le_bytes());
+                    ResponseType::Ack => (),
+                    ResponseType::Nak => {
+                        buf[1..5].copy_from_slice(&crc.to_le_bytes());
                     }
                 }
             }
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VQCapabilities { capabilities } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1..=Capabilities::SIZE].copy_from_slice(&capabilities.to_le_bytes());
+            }
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VJedecId { id } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1..4].copy_from_slice(id);
+            }
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VQDiagnostics {
+                dropped_writes,
+                parse_errors,
+            } => {
+                buf[0] = ResponseType::Ack as u8;
+                buf[1..5].copy_from_slice(&dropped_writes.to_le_bytes());
+                buf[5..9].copy_from_slice(&parse_errors.to_le_bytes());
+            }
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VLoopback { res, offset } => {
+                buf[0] = *res as u8;
+                match res {
+                    ResponseType::Ack => (),
+                    ResponseType::Nak => buf[1] = *offset,
+                }
+            }
+            ResponsePacket::Streamed => (),
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VSet { res } => {
+                buf[0] = *res as u8;
+            }
         }
 
         Ok(packet_size)
@@ -135,14 +542,45 @@ impl ResponsePacket {
         match self {
             ResponsePacket::Nop => 1,
             ResponsePacket::QIface { .. } => 3,
-            ResponsePacket::QCmdMap { .. } => 33,
-            ResponsePacket::QPgmName { .. } => 17,
+            ResponsePacket::QCmdMap { .. } => 1 + CMD_MAP_SIZE,
+            ResponsePacket::QPgmName { .. } => 1 + PGM_NAME_SIZE,
             ResponsePacket::QSerBuf { .. } => 3,
             ResponsePacket::QBusType { .. } => 2,
+            ResponsePacket::QChipSize { res, .. } => match res {
+                ResponseType::Nak => 1,
+                ResponseType::Ack => 2,
+            },
+            ResponsePacket::QOpBuf { .. } => 3,
+            ResponsePacket::QWrnMaxLen { .. } => 4,
+            ResponsePacket::QRdnMaxLen { .. } => 4,
+            #[cfg(feature = "memory-read")]
+            ResponsePacket::RByte { .. } => 2,
+            ResponsePacket::OpBuf { .. } => 1,
             ResponsePacket::SyncNop => 2,
             ResponsePacket::SBusType { .. } => 1,
-            ResponsePacket::SpiOp { rlen, .. } => rlen + 1,
             ResponsePacket::SSpiFreq { .. } => 5,
+            ResponsePacket::SPinState { .. } => 1,
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VQSpiConfig { .. } => 5,
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VVerifyCrc { res, .. } => match res {
+                ResponseType::Ack => 1,
+                ResponseType::Nak => 5,
+            },
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VQCapabilities { .. } => 1 + Capabilities::SIZE,
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VJedecId { .. } => 4,
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VQDiagnostics { .. } => 9,
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VLoopback { res, .. } => match res {
+                ResponseType::Ack => 1,
+                ResponseType::Nak => 2,
+            },
+            ResponsePacket::Streamed => 0,
+            #[cfg(feature = "vendor-extension")]
+            ResponsePacket::VSet { .. } => 1,
         }
     }
 }
@@ -156,30 +594,270 @@ pub enum OpCode {
     QPgmName = 0x03,
     QSerBuf = 0x04,
     QBusType = 0x05,
-    _QChipSize = 0x06,
-    _QOpBuf = 0x07,
-    _QWrnMaxLen = 0x08,
-    _RByte = 0x09,
-    _RNBytes = 0x0A,
-    _OInit = 0x0B,
-    _OWriteB = 0x0C,
-    _OWriteN = 0x0D,
-    _ODelay = 0x0E,
-    _OExec = 0x0F,
+    QChipSize = 0x06,
+    QOpBuf = 0x07,
+    QWrnMaxLen = 0x08,
+    RByte = 0x09,
+    RNBytes = 0x0A,
+    OInit = 0x0B,
+    OWriteB = 0x0C,
+    OWriteN = 0x0D,
+    ODelay = 0x0E,
+    OExec = 0x0F,
     SyncNop = 0x10,
-    _QRdnMaxLen = 0x11,
+    QRdnMaxLen = 0x11,
     SBusType = 0x12,
     OSpiOp = 0x13,
     SSpiFreq = 0x14,
     SPinState = 0x15,
+    // Vendor extensions
+    #[cfg(feature = "vendor-extension")]
+    VProbe = 0x80,
+    #[cfg(feature = "vendor-extension")]
+    VQSpiConfig = 0x81,
+    #[cfg(feature = "vendor-extension")]
+    VVerifyCrc = 0x82,
+    #[cfg(feature = "vendor-extension")]
+    VMultiRead = 0x83,
+    #[cfg(feature = "vendor-extension")]
+    VSResponseDelay = 0x84,
+    #[cfg(feature = "vendor-extension")]
+    VSCrc8Mode = 0x85,
+    #[cfg(feature = "vendor-extension")]
+    VFactoryReset = 0x86,
+    #[cfg(feature = "vendor-extension")]
+    VReadSfdp = 0x87,
+    #[cfg(feature = "vendor-extension")]
+    VSSpiMode = 0x88,
+    #[cfg(feature = "vendor-extension")]
+    VSChipSize = 0x89,
+    #[cfg(feature = "bootloader-reset")]
+    VBootloader = 0x8A,
+    #[cfg(feature = "vendor-extension")]
+    VSCsHighTime = 0x8B,
+    #[cfg(feature = "vendor-extension")]
+    VLoopback = 0x8C,
+    #[cfg(feature = "vendor-extension")]
+    VQCapabilities = 0x8D,
+    #[cfg(feature = "vendor-extension")]
+    VSChipSelect = 0x8E,
+    #[cfg(feature = "vendor-extension")]
+    VQDiagnostics = 0x8F,
+    #[cfg(feature = "vendor-extension")]
+    VAbort = 0x90,
+    #[cfg(feature = "vendor-extension")]
+    VSPinSpeed = 0x91,
+    #[cfg(feature = "vendor-extension")]
+    VSCsSetupTime = 0x92,
+    #[cfg(feature = "vendor-extension")]
+    VSaveSettings = 0x93,
+    #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+    VSAddressEndian = 0x94,
+    #[cfg(feature = "vendor-extension")]
+    VReadJedecId = 0x95,
+    #[cfg(feature = "diagnostics")]
+    VReadPanic = 0x96,
+    #[cfg(feature = "vendor-extension")]
+    VSFrameSize = 0x97,
+    #[cfg(feature = "vendor-extension")]
+    VSReadRetries = 0x98,
 }
 
-impl OpCode {
-    pub fn from_u8(n: u8) -> Option<OpCode> {
-        if n <= 0x15 {
-            Some(unsafe { core::mem::transmute(n) })
+/// Builds the Q_PGMNAME reply, PGM_NAME followed by PGM_VERSION and NUL padded. The name is cut
+/// short rather than the version when both don't fit in the 16 bytes.
+pub fn pgm_name() -> [u8; PGM_NAME_SIZE] {
+    let mut pgm_name = [0u8; PGM_NAME_SIZE];
+    let version = &PGM_VERSION.as_bytes()[..PGM_VERSION.len().min(pgm_name.len())];
+    let name_len = PGM_NAME.len().min(pgm_name.len() - version.len());
+    pgm_name[..name_len].copy_from_slice(&PGM_NAME.as_bytes()[..name_len]);
+    pgm_name[name_len..name_len + version.len()].copy_from_slice(version);
+    pgm_name
+}
+
+/// Q_CMDMAP bitmap, bit n is set if opcode n is supported
+pub const CMD_MAP: [u8; CMD_MAP_SIZE] = cmd_map();
+
+const fn cmd_map() -> [u8; CMD_MAP_SIZE] {
+    let mut cmd_map = [0u8; CMD_MAP_SIZE];
+    let mut i = 0;
+    while i < SUPPORTED_OPCODES.len() {
+        let n = SUPPORTED_OPCODES[i] as usize;
+        cmd_map[n / 8] |= 1 << (n % 8);
+        i += 1;
+    }
+    cmd_map
+}
+
+/// The larger of `a` and `b`, Ord::max can't be called in constants
+const fn larger(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Whether `opcode` is advertised in CMD_MAP
+pub const fn is_supported(opcode: OpCode) -> bool {
+    let n = opcode as usize;
+    CMD_MAP[n / 8] & (1 << (n % 8)) != 0
+}
+
+// CMD_MAP must advertise exactly the opcodes with a handler, or flashrom either skips a command
+// that works or sends one that gets NAKed. Checked over every opcode this build decodes, so a
+// cfg missing from one of the lists fails the build.
+const _: () = {
+    let mut n = 0;
+    while n <= u8::MAX as u16 {
+        if let Some(opcode) = OpCode::from_u8(n as u8) {
+            assert!(
+                opcode.has_handler() == is_supported(opcode),
+                "SUPPORTED_OPCODES disagrees with the opcodes handle_command() serves"
+            );
+        }
+        n += 1;
+    }
+};
+
+/// CRC-8 (polynomial 0x07, no reflection) over command bytes
+#[cfg(feature = "vendor-extension")]
+pub fn crc8_update(crc: u8, byte: u8) -> u8 {
+    (0..8).fold(crc ^ byte, |crc, _| {
+        if crc & 0x80 != 0 {
+            (crc << 1) ^ 0x07
         } else {
-            None
+            crc << 1
         }
+    })
+}
+
+/// Splits the V_READ_PANIC reply into its header, an Ack with the 16 bit little endian length of
+/// the message, and the message. One too long for the length field is cut to fit.
+#[cfg(feature = "diagnostics")]
+pub fn panic_reply(message: &[u8]) -> ([u8; 3], &[u8]) {
+    let message = &message[..message.len().min(u16::MAX as usize)];
+    let [len_lo, len_hi] = (message.len() as u16).to_le_bytes();
+    ([ResponseType::Ack as u8, len_lo, len_hi], message)
+}
+
+impl OpCode {
+    /// False for the opcodes handle_command() answers with NotImplemented. Its match has no
+    /// catch-all, so a new opcode can't be added without deciding which it is.
+    pub const fn has_handler(self) -> bool {
+        cfg!(feature = "memory-read") || !matches!(self, OpCode::RByte | OpCode::RNBytes)
+    }
+
+    pub const fn from_u8(n: u8) -> Option<OpCode> {
+        match n {
+            0x00 => Some(OpCode::Nop),
+            0x01 => Some(OpCode::QIface),
+            0x02 => Some(OpCode::QCmdMap),
+            0x03 => Some(OpCode::QPgmName),
+            0x04 => Some(OpCode::QSerBuf),
+            0x05 => Some(OpCode::QBusType),
+            0x06 => Some(OpCode::QChipSize),
+            0x07 => Some(OpCode::QOpBuf),
+            0x08 => Some(OpCode::QWrnMaxLen),
+            0x09 => Some(OpCode::RByte),
+            0x0A => Some(OpCode::RNBytes),
+            0x0B => Some(OpCode::OInit),
+            0x0C => Some(OpCode::OWriteB),
+            0x0D => Some(OpCode::OWriteN),
+            0x0E => Some(OpCode::ODelay),
+            0x0F => Some(OpCode::OExec),
+            0x10 => Some(OpCode::SyncNop),
+            0x11 => Some(OpCode::QRdnMaxLen),
+            0x12 => Some(OpCode::SBusType),
+            0x13 => Some(OpCode::OSpiOp),
+            0x14 => Some(OpCode::SSpiFreq),
+            0x15 => Some(OpCode::SPinState),
+            #[cfg(feature = "vendor-extension")]
+            0x80 => Some(OpCode::VProbe),
+            #[cfg(feature = "vendor-extension")]
+            0x81 => Some(OpCode::VQSpiConfig),
+            #[cfg(feature = "vendor-extension")]
+            0x82 => Some(OpCode::VVerifyCrc),
+            #[cfg(feature = "vendor-extension")]
+            0x83 => Some(OpCode::VMultiRead),
+            #[cfg(feature = "vendor-extension")]
+            0x84 => Some(OpCode::VSResponseDelay),
+            #[cfg(feature = "vendor-extension")]
+            0x85 => Some(OpCode::VSCrc8Mode),
+            #[cfg(feature = "vendor-extension")]
+            0x86 => Some(OpCode::VFactoryReset),
+            #[cfg(feature = "vendor-extension")]
+            0x87 => Some(OpCode::VReadSfdp),
+            #[cfg(feature = "vendor-extension")]
+            0x88 => Some(OpCode::VSSpiMode),
+            #[cfg(feature = "vendor-extension")]
+            0x89 => Some(OpCode::VSChipSize),
+            #[cfg(feature = "bootloader-reset")]
+            0x8A => Some(OpCode::VBootloader),
+            #[cfg(feature = "vendor-extension")]
+            0x8B => Some(OpCode::VSCsHighTime),
+            #[cfg(feature = "vendor-extension")]
+            0x8C => Some(OpCode::VLoopback),
+            #[cfg(feature = "vendor-extension")]
+            0x8D => Some(OpCode::VQCapabilities),
+            #[cfg(feature = "vendor-extension")]
+            0x8E => Some(OpCode::VSChipSelect),
+            #[cfg(feature = "vendor-extension")]
+            0x8F => Some(OpCode::VQDiagnostics),
+            #[cfg(feature = "vendor-extension")]
+            0x90 => Some(OpCode::VAbort),
+            #[cfg(feature = "vendor-extension")]
+            0x91 => Some(OpCode::VSPinSpeed),
+            #[cfg(feature = "vendor-extension")]
+            0x92 => Some(OpCode::VSCsSetupTime),
+            #[cfg(feature = "vendor-extension")]
+            0x93 => Some(OpCode::VSaveSettings),
+            #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+            0x94 => Some(OpCode::VSAddressEndian),
+            #[cfg(feature = "vendor-extension")]
+            0x95 => Some(OpCode::VReadJedecId),
+            #[cfg(feature = "diagnostics")]
+            0x96 => Some(OpCode::VReadPanic),
+            #[cfg(feature = "vendor-extension")]
+            0x97 => Some(OpCode::VSFrameSize),
+            #[cfg(feature = "vendor-extension")]
+            0x98 => Some(OpCode::VSReadRetries),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn panic_reply_without_panic() {
+        let (header, message) = panic_reply(&[]);
+        assert_eq!(header, [ResponseType::Ack as u8, 0, 0]);
+        assert!(message.is_empty());
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn panic_reply_frames_message() {
+        let text = b"panicked at 'index out of bounds', src/serprog.rs:42:5";
+        let (header, message) = panic_reply(text);
+        assert_eq!(header, [ResponseType::Ack as u8, text.len() as u8, 0]);
+        assert_eq!(message, &text[..]);
+
+        let long = [b'x'; 300];
+        let (header, message) = panic_reply(&long);
+        assert_eq!(header, [ResponseType::Ack as u8, 44, 1]);
+        assert_eq!(message.len(), 300);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn panic_reply_cuts_message_to_length_field() {
+        let long = [b'x'; u16::MAX as usize + 10];
+        let (header, message) = panic_reply(&long);
+        assert_eq!(header, [ResponseType::Ack as u8, 0xFF, 0xFF]);
+        assert_eq!(message.len(), u16::MAX as usize);
     }
 }
@@ -0,0 +1,154 @@
+#[cfg(feature = "vendor-extension")]
+use crate::spi::DUMMY_BYTE;
+use crate::{
+    spi::{SpiError, SpiManager},
+    timing::Deadline,
+};
+use stm32f1xx_hal::time::Hertz;
+
+// JEDEC read identification
+#[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+const RDID: u8 = 0x9F;
+// Write enable
+const WREN: u8 = 0x06;
+// Page program
+const PP: u8 = 0x02;
+// Read status register
+const RDSR: u8 = 0x05;
+// Write in progress bit of the status register
+const SR_WIP: u8 = 1 << 0;
+// A page program wraps around within pages of this size
+pub(crate) const PAGE_SIZE: usize = 256;
+// Read data bytes
+#[cfg(any(feature = "vendor-extension", feature = "memory-read"))]
+const READ: u8 = 0x03;
+// Read serial flash discoverable parameters
+#[cfg(feature = "vendor-extension")]
+const RDSFDP: u8 = 0x5A;
+
+// Commands that only read, so repeating them changes nothing: READ, FAST_READ, RDSR, RDSR2,
+// RDSR3, RDSFDP, the manufacturer/device ID, the unique ID and RDID
+#[cfg(feature = "vendor-extension")]
+const READ_ONLY_COMMANDS: [u8; 9] = [0x03, 0x0B, 0x05, 0x35, 0x15, 0x5A, 0x90, 0x4B, 0x9F];
+
+/// Whether a transaction sending `tx` can be repeated without side effects. Nothing sent at all
+/// only clocks in data.
+#[cfg(feature = "vendor-extension")]
+pub(crate) fn is_read_only(tx: &[u8]) -> bool {
+    tx.first()
+        .map_or(true, |opcode| READ_ONLY_COMMANDS.contains(opcode))
+}
+
+/// Flash chip as identified by its JEDEC ID. Only the size is kept, V_READ_JEDEC_ID hands the
+/// raw ID to hosts that want it.
+#[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Chip {
+    /// Chip size in 2^n bytes
+    pub(crate) size_log2: u8,
+}
+
+#[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+impl Chip {
+    /// Reads the JEDEC ID of the attached chip, returns None if nothing sensible answered
+    pub(crate) fn probe(spi_manager: &mut SpiManager) -> Option<Self> {
+        match read_jedec_id(spi_manager).ok()? {
+            // Floating or shorted MISO
+            [0x00, 0x00, 0x00] | [0xff, 0xff, 0xff] => None,
+            // Most vendors encode the capacity as log2 of the size in bytes
+            [_, _, size_log2] if (10..=31).contains(&size_log2) => Some(Self { size_log2 }),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the manufacturer ID followed by the two device ID bytes, as the chip returns them
+#[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+pub(crate) fn read_jedec_id(spi_manager: &mut SpiManager) -> Result<[u8; 3], SpiError> {
+    let mut id = [0u8; 3];
+    spi_manager.read_write(&[RDID], &mut id)?;
+    Ok(id)
+}
+
+/// Reads `buf.len()` bytes starting at `addr` with the READ command
+#[cfg(any(feature = "vendor-extension", feature = "memory-read"))]
+pub(crate) fn read(
+    spi_manager: &mut SpiManager,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), SpiError> {
+    let [_, a2, a1, a0] = addr.to_be_bytes();
+    spi_manager.read_write(&[READ, a2, a1, a0], buf)
+}
+
+/// Reads `buf.len()` bytes of the SFDP table starting at `addr`, RDSFDP needs a dummy byte
+/// between the address and the data
+#[cfg(feature = "vendor-extension")]
+pub(crate) fn read_sfdp(
+    spi_manager: &mut SpiManager,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), SpiError> {
+    let [_, a2, a1, a0] = addr.to_be_bytes();
+    spi_manager.read_write(&[RDSFDP, a2, a1, a0, DUMMY_BYTE], buf)
+}
+
+/// Enables writes and page programs `data` at `addr`, then waits for the chip to finish until
+/// `deadline`. The data must not cross a page boundary, the chip would wrap around within the page.
+pub(crate) fn program(
+    spi_manager: &mut SpiManager,
+    addr: u32,
+    data: &[u8],
+    deadline: &Deadline,
+) -> Result<(), SpiError> {
+    spi_manager.read_write(&[WREN], &mut [])?;
+
+    let [_, a2, a1, a0] = addr.to_be_bytes();
+    spi_manager.select()?;
+    let res = spi_manager
+        .write(&[PP, a2, a1, a0])
+        .and_then(|_| spi_manager.write(data));
+    spi_manager.deselect();
+    res?;
+
+    wait_ready(spi_manager, deadline)
+}
+
+/// Programs `data` at `addr` with one page program per page it touches, each waited on for up to
+/// `timeout_us`
+pub(crate) fn program_pages(
+    spi_manager: &mut SpiManager,
+    addr: u32,
+    data: &[u8],
+    sysclk: Hertz,
+    timeout_us: u32,
+) -> Result<(), SpiError> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let page_addr = addr.wrapping_add(offset as u32);
+        let n = page_chunk_len(page_addr, data.len() - offset);
+        let deadline = Deadline::after_us(sysclk, timeout_us);
+        program(spi_manager, page_addr, &data[offset..offset + n], &deadline)?;
+        offset += n;
+    }
+    Ok(())
+}
+
+/// How many of `len` bytes starting at `addr` fit before the next page boundary
+pub(crate) fn page_chunk_len(addr: u32, len: usize) -> usize {
+    len.min(PAGE_SIZE - addr as usize % PAGE_SIZE)
+}
+
+/// Polls the status register until the chip is done programming or `deadline` passes
+fn wait_ready(spi_manager: &mut SpiManager, deadline: &Deadline) -> Result<(), SpiError> {
+    loop {
+        let mut status = [0u8; 1];
+        spi_manager.read_write(&[RDSR], &mut status)?;
+        if status[0] & SR_WIP == 0 {
+            return Ok(());
+        }
+        if deadline.expired() {
+            return Err(SpiError::Timeout);
+        }
+    }
+}
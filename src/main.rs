@@ -1,36 +1,61 @@
-// std and main are not available for bare metal software
-#![no_std]
-#![no_main]
+// std and main are not available for bare metal software. The unit tests run on the host where
+// both are, build them for its target, e.g. `cargo test --target x86_64-unknown-linux-gnu`.
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Most of the firmware is only reached from main(), which the test harness replaces
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 mod data_utils;
+mod flash;
+mod op_buf;
+#[cfg(not(test))]
+mod panic;
 mod serprog;
+#[cfg(feature = "vendor-extension")]
+mod settings;
 mod spi;
+mod timing;
+mod usb_config;
 
+use core::fmt::Write;
 use cortex_m::asm::delay;
 use cortex_m_rt::entry; // The runtime
-use data_utils::OpCode;
 use embedded_hal::digital::v2::OutputPin;
-use serprog::SerProg;
+use heapless::String;
+use serprog::{SerProg, SerProgError};
+#[cfg(feature = "bootloader-reset")]
+use stm32f1xx_hal::backup_domain::BackupDomain;
+#[cfg(feature = "vendor-extension")]
+use stm32f1xx_hal::flash::{FlashSize, SectorSize};
+#[cfg(all(feature = "hse12", feature = "hsi"))]
+compile_error!("Features hse12 and hsi pick different clock sources, enable only one");
+#[cfg(all(feature = "miso-pull-up", feature = "miso-pull-down"))]
+compile_error!("Features miso-pull-up and miso-pull-down conflict, enable only one");
 use stm32f1xx_hal::{
     afio::MAPR,
-    gpio::gpioa::CRL,
+    flash::ACR,
+    gpio::{gpioa::CRL, State},
     pac,
     prelude::*,
-    rcc::APB2,
+    rcc::{Clocks, APB2, CFGR},
     usb::{Peripheral, UsbBus},
+    watchdog::IndependentWatchdog,
 };
-use usb_device::prelude::{UsbDeviceBuilder, UsbVidPid};
-use usbd_serial::{SerialPort, USB_CLASS_CDC};
-
-#[allow(unused_imports)]
-use panic_halt; // When a panic occurs, stop the microcontroller
+use usb_config::UsbConfig;
+use usbd_serial::SerialPort;
 
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     // Get handles to the hardware objects. These functions can only be called
     // once, so that the borrowchecker can ensure you don't reconfigure
     // something by accident.
     let dp = pac::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+
+    // Timeouts are measured with the cycle counter
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
 
     // GPIO pins on the STM32F1 must be driven by the APB2 peripheral clock.
     // This must be enabled first. The HAL provides some abstractions for
@@ -38,18 +63,35 @@ fn main() -> ! {
     let mut flash = dp.FLASH.constrain();
     let mut rcc = dp.RCC.constrain();
 
+    // Checked before the watchdog is started, the ROM bootloader wouldn't feed it
+    #[cfg(feature = "bootloader-reset")]
+    let backup = {
+        let mut pwr = dp.PWR;
+        let mut backup = rcc.bkp.constrain(dp.BKP, &mut rcc.apb1, &mut pwr);
+        enter_bootloader_if_requested(&mut backup);
+        backup
+    };
+
+    // Recover from anything that wedges the firmware without a replug
+    let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+    watchdog.start(data_utils::WATCHDOG_TIMEOUT_MS.ms());
+
     // Configure the clock
-    let clocks = rcc
-        .cfgr
-        .use_hse(8.mhz())
-        .sysclk(48.mhz())
-        .pclk1(24.mhz())
-        .freeze(&mut flash.acr);
+    let clocks = setup_clocks(rcc.cfgr, &mut flash.acr);
 
     assert!(clocks.usbclk_valid());
 
     let mut afio = dp.AFIO.constrain(&mut rcc.apb2);
     let mut gpioa = dp.GPIOA.split(&mut rcc.apb2);
+    let mut gpioc = dp.GPIOC.split(&mut rcc.apb2);
+
+    // Blue Pill LED, active low
+    let led = gpioc
+        .pc13
+        .into_push_pull_output_with_state(&mut gpioc.crh, State::High);
+
+    // Unique per board so hosts can tell several dongles apart
+    let serial_number = unique_id_serial();
 
     // Pull down PA12 (D+ pin) to send a RESET condition to the USB bus
     let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
@@ -66,35 +108,127 @@ fn main() -> ! {
 
     let serial = SerialPort::new(&usb_bus);
 
-    // VID: ST Microelectronics
-    // PID: STM32
-    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x0483, 0x5740))
-        .manufacturer("nankeen.me")
-        .product("STM32 serprog")
-        .serial_number("CAFEBABE")
-        .device_class(USB_CLASS_CDC)
-        .build();
+    let usb_dev = UsbConfig::default().build(&usb_bus, &serial_number);
 
     // Setup SPI
-    let (cs, sck, miso, mosi) = (gpioa.pa4, gpioa.pa5, gpioa.pa6, gpioa.pa7);
+    #[cfg(not(feature = "spi2"))]
+    let (mut spi_cr, cs, sck, miso, mosi, spi_periph, spi_apb) = (
+        gpioa.crl,
+        (
+            gpioa.pa4,
+            #[cfg(feature = "vendor-extension")]
+            gpioa.pa3,
+            #[cfg(feature = "vendor-extension")]
+            gpioa.pa2,
+        ),
+        gpioa.pa5,
+        gpioa.pa6,
+        gpioa.pa7,
+        dp.SPI1,
+        &mut rcc.apb2,
+    );
+    #[cfg(feature = "spi2")]
+    let (mut spi_cr, cs, sck, miso, mosi, spi_periph, spi_apb) = {
+        let gpiob = dp.GPIOB.split(&mut rcc.apb2);
+        (
+            gpiob.crh,
+            (
+                gpiob.pb12,
+                #[cfg(feature = "vendor-extension")]
+                gpiob.pb11,
+                #[cfg(feature = "vendor-extension")]
+                gpiob.pb10,
+            ),
+            gpiob.pb13,
+            gpiob.pb14,
+            gpiob.pb15,
+            dp.SPI2,
+            &mut rcc.apb1,
+        )
+    };
 
-    let spi = spi::SpiManager::new(cs, sck, miso, mosi, dp.SPI1, clocks);
-    let mut serprog = SerProg::new(spi, serial, usb_dev);
+    let spi = spi::SpiManager::new(cs, sck, miso, mosi, spi_periph, clocks, &mut spi_cr);
+    #[cfg(feature = "vendor-extension")]
+    let crc = dp.CRC.new(&mut rcc.ahb);
+    #[cfg(feature = "vendor-extension")]
+    let flash_writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+    let mut serprog: SerProg<_, _> = SerProg::new(
+        spi,
+        serial,
+        usb_dev,
+        led,
+        watchdog,
+        clocks,
+        #[cfg(feature = "vendor-extension")]
+        crc,
+        #[cfg(feature = "vendor-extension")]
+        flash_writer,
+        #[cfg(feature = "bootloader-reset")]
+        backup,
+    );
+    #[cfg(feature = "vendor-extension")]
+    serprog.restore_settings(&mut afio.mapr, &mut spi_cr, spi_apb);
     let mut response_buffer = [0u8; data_utils::ResponsePacket::MAX_SIZE];
 
     // Loop to handle commands
     loop {
-        // Read opcode from USB serial
-        if let Some(cmd) = OpCode::from_u8(serprog.read_u8()) {
-            // Pass it to the command handler
-            if let Ok(res) =
-                serprog.handle_command(cmd, &mut afio.mapr, &mut gpioa.crl, &mut rcc.apb2)
-            {
-                // Serialize and respond
-                if let Ok(n) = res.to_bytes(&mut response_buffer) {
-                    serprog.send_response(&response_buffer[..n]);
+        // Read opcode from USB serial and pass it to the command handler
+        let res = match serprog.read_opcode() {
+            Ok(cmd) => serprog.handle_command(cmd, &mut afio.mapr, &mut spi_cr, spi_apb),
+            Err(err) => Err(err),
+        };
+        match res {
+            // Serialize and respond
+            Ok(res) => match res.to_bytes(&mut response_buffer) {
+                // Nothing to do about a dead link, the host will resync
+                Ok(n) => {
+                    serprog.send_response(&response_buffer[..n]).ok();
                 }
-            }
+                Err(source) => serprog.send_error(&SerProgError::Response { source }),
+            },
+            Err(err) => serprog.send_error(&err),
         }
+        // Streamed replies are only flushed once complete, so chunks keep the endpoint busy
+        serprog.flush().ok();
+    }
+}
+
+/// Jumps to the ROM bootloader if the host asked for it before the last reset. Nothing but the
+/// backup domain is set up yet, so the bootloader finds the chip as it is out of reset.
+#[cfg(feature = "bootloader-reset")]
+fn enter_bootloader_if_requested(backup: &mut BackupDomain) {
+    // System memory, it starts with the bootloader's vector table
+    const SYSTEM_MEMORY: *const u32 = 0x1FFF_F000 as *const u32;
+
+    if backup.read_data_register_low(data_utils::BOOTLOADER_MAGIC_REGISTER)
+        == data_utils::BOOTLOADER_MAGIC
+    {
+        // One shot, the next reset starts the firmware again
+        backup.write_data_register_low(data_utils::BOOTLOADER_MAGIC_REGISTER, 0);
+        unsafe { cortex_m::asm::bootload(SYSTEM_MEMORY) }
+    }
+}
+
+/// Runs the core at 48 MHz off the PLL, which then also gives USB its 48 MHz. The PLL input is
+/// HSE with an 8 or 12 MHz crystal multiplied by 6 or 4, or HSI/2 multiplied by 12.
+fn setup_clocks(cfgr: CFGR, acr: &mut ACR) -> Clocks {
+    #[cfg(not(any(feature = "hse12", feature = "hsi")))]
+    let cfgr = cfgr.use_hse(8.mhz());
+    #[cfg(feature = "hse12")]
+    let cfgr = cfgr.use_hse(12.mhz());
+
+    cfgr.sysclk(48.mhz()).pclk1(24.mhz()).freeze(acr)
+}
+
+/// Formats the 96-bit factory programmed unique device ID as hex
+fn unique_id_serial() -> String<24> {
+    // Device electronic signature, see the reference manual
+    const UID_BASE: *const u8 = 0x1FFF_F7E8 as *const u8;
+
+    let mut serial = String::new();
+    for i in 0..12 {
+        let byte = unsafe { core::ptr::read_volatile(UID_BASE.add(i)) };
+        write!(serial, "{:02X}", byte).ok();
     }
+    serial
 }
@@ -0,0 +1,139 @@
+use crate::data_utils::{OpCode, OP_BUF_SIZE};
+
+// Size of each op, the opcode and its parameters, O_WRITEN's data comes on top
+const WRITE_B_SIZE: usize = 1 + 3 + 1;
+pub(crate) const WRITE_N_HEADER_SIZE: usize = 1 + 3 + 3;
+const DELAY_SIZE: usize = 1 + 4;
+
+/// Operation queued until O_EXEC
+pub(crate) enum Op<'a> {
+    WriteB { addr: u32, byte: u8 },
+    WriteN { addr: u32, data: &'a [u8] },
+    Delay { us: u32 },
+}
+
+/// Operation buffer filled by O_WRITEB, O_WRITEN and O_DELAY. Ops are stored the way the commands
+/// encode them on the wire.
+pub(crate) struct OpBuf {
+    buf: [u8; OP_BUF_SIZE],
+    /// End of the committed ops
+    len: usize,
+    /// End of the op being staged, only part of the queue once committed
+    staged: usize,
+}
+
+impl OpBuf {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: [0; OP_BUF_SIZE],
+            len: 0,
+            staged: 0,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+        self.staged = 0;
+    }
+
+    /// Starts staging an op of `size` bytes, dropping any uncommitted one. Returns false if it
+    /// doesn't fit.
+    fn begin(&mut self, size: usize) -> bool {
+        self.staged = self.len;
+        OP_BUF_SIZE - self.len >= size
+    }
+
+    /// Appends to the op being staged, within the size given to begin()
+    pub(crate) fn stage(&mut self, bytes: &[u8]) {
+        self.buf[self.staged..self.staged + bytes.len()].copy_from_slice(bytes);
+        self.staged += bytes.len();
+    }
+
+    /// Adds the staged op to the queue
+    pub(crate) fn commit(&mut self) {
+        self.len = self.staged;
+    }
+
+    pub(crate) fn begin_write_b(&mut self, addr: u32, byte: u8) -> bool {
+        if !self.begin(WRITE_B_SIZE) {
+            return false;
+        }
+        self.stage(&[OpCode::OWriteB as u8]);
+        self.stage(&addr.to_le_bytes()[..3]);
+        self.stage(&[byte]);
+        true
+    }
+
+    /// The `len` bytes of data are staged by the caller as they arrive
+    pub(crate) fn begin_write_n(&mut self, addr: u32, len: usize) -> bool {
+        if !self.begin(WRITE_N_HEADER_SIZE + len) {
+            return false;
+        }
+        self.stage(&[OpCode::OWriteN as u8]);
+        self.stage(&(len as u32).to_le_bytes()[..3]);
+        self.stage(&addr.to_le_bytes()[..3]);
+        true
+    }
+
+    pub(crate) fn begin_delay(&mut self, us: u32) -> bool {
+        if !self.begin(DELAY_SIZE) {
+            return false;
+        }
+        self.stage(&[OpCode::ODelay as u8]);
+        self.stage(&us.to_le_bytes());
+        true
+    }
+
+    /// Committed ops in the order they were queued
+    pub(crate) fn ops(&self) -> Ops<'_> {
+        Ops {
+            buf: &self.buf[..self.len],
+        }
+    }
+}
+
+pub(crate) struct Ops<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Ops<'a> {
+    type Item = Op<'a>;
+
+    fn next(&mut self) -> Option<Op<'a>> {
+        let (&opcode, params) = self.buf.split_first()?;
+        let (op, size) = match OpCode::from_u8(opcode)? {
+            OpCode::OWriteB => (
+                Op::WriteB {
+                    addr: u24(&params[..3]),
+                    byte: params[3],
+                },
+                WRITE_B_SIZE,
+            ),
+            OpCode::OWriteN => {
+                let len = u24(&params[..3]) as usize;
+                let data_start = WRITE_N_HEADER_SIZE - 1;
+                (
+                    Op::WriteN {
+                        addr: u24(&params[3..6]),
+                        data: &params[data_start..data_start + len],
+                    },
+                    WRITE_N_HEADER_SIZE + len,
+                )
+            }
+            OpCode::ODelay => (
+                Op::Delay {
+                    us: u32::from_le_bytes([params[0], params[1], params[2], params[3]]),
+                },
+                DELAY_SIZE,
+            ),
+            _ => return None,
+        };
+
+        self.buf = &self.buf[size..];
+        Some(op)
+    }
+}
+
+fn u24(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
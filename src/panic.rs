@@ -0,0 +1,43 @@
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{self, Ordering},
+};
+use stm32f1xx_hal::pac;
+
+/// Deselects the flash chip before halting, so a crash mid-transfer doesn't leave CS asserted. The
+/// watchdog resets the MCU shortly after. This takes the place of panic-halt, which would halt
+/// with CS as it was, and only hands the message on to panic-persist in diagnostics builds.
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // The SPI manager may own the pins at this point, so drive every CS high through the set
+    // half of BSRR, a single write that can't disturb the other pins
+    #[cfg(not(feature = "spi2"))]
+    unsafe {
+        (*pac::GPIOA::ptr()).bsrr.write(|w| {
+            let w = w.bs4().set_bit();
+            #[cfg(feature = "vendor-extension")]
+            let w = w.bs3().set_bit().bs2().set_bit();
+            w
+        })
+    };
+    #[cfg(feature = "spi2")]
+    unsafe {
+        (*pac::GPIOB::ptr()).bsrr.write(|w| {
+            let w = w.bs12().set_bit();
+            #[cfg(feature = "vendor-extension")]
+            let w = w.bs11().set_bit().bs10().set_bit();
+            w
+        })
+    };
+
+    // Survives the reset, V_READ_PANIC hands it to the host
+    #[cfg(feature = "diagnostics")]
+    panic_persist::report_panic_info(info);
+    #[cfg(not(feature = "diagnostics"))]
+    let _ = info;
+
+    loop {
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
@@ -1,22 +1,95 @@
+#[cfg(feature = "diagnostics")]
+use crate::data_utils::panic_reply;
+#[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+use crate::data_utils::AddressEndian;
+#[cfg(feature = "vendor-extension")]
+use crate::data_utils::{
+    crc8_update, firmware_version, Capabilities, MAX_READ_REGIONS, MAX_RESPONSE_DELAY_US,
+};
+#[cfg(feature = "bootloader-reset")]
+use crate::data_utils::{BOOTLOADER_MAGIC, BOOTLOADER_MAGIC_REGISTER};
+#[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+use crate::flash::Chip;
 use crate::{
     data_utils::{
-        OpCode, ResponsePacket, ResponseType, CMD_MAP, I_FACE_VERSION, PGM_NAME, SUPPORTED_BUS,
+        pgm_name, BusType, DataError, OpCode, ResponsePacket, ResponseType, CMD_MAP,
+        COMMAND_TIMEOUT_US, I_FACE_VERSION, LED_FLASH_US, MAX_BUFFER_SIZE, MAX_READ_N,
+        MAX_SPI_OP_WRITE, MAX_WRITE_N, MAX_WRITE_N_STREAMED, OP_BUF_SIZE, PROGRAM_TIMEOUT_US,
+        RESPONSE_DELAY_US, RESPONSE_TIMEOUT_US, SER_BUF_SIZE, SUPPORTED_BUS,
     },
-    spi::SpiManager,
+    flash,
+    op_buf::{Op, OpBuf},
+    spi::{SpiApb, SpiCr, SpiError, SpiManager},
+    timing::{self, Deadline},
+};
+#[cfg(feature = "vendor-extension")]
+use crate::{
+    settings::Settings,
+    spi::{mode_from_u8, FrameSize, PinSpeed},
 };
-use embedded_hal::{digital::v2::OutputPin, serial::Read};
+#[cfg(feature = "bootloader-reset")]
+use cortex_m::peripheral::SCB;
+use embedded_hal::{digital::v2::OutputPin, watchdog::Watchdog};
 use snafu::Snafu;
-use stm32f1xx_hal::{afio::MAPR, gpio::gpioa::CRL, rcc::APB2, time::U32Ext};
-use usb_device::{bus::UsbBus, prelude::UsbDevice};
+#[cfg(feature = "bootloader-reset")]
+use stm32f1xx_hal::backup_domain::BackupDomain;
+use stm32f1xx_hal::{afio::MAPR, rcc::Clocks, time::U32Ext, watchdog::IndependentWatchdog};
+#[cfg(feature = "vendor-extension")]
+use stm32f1xx_hal::{crc::Crc, flash::FlashWriter};
+use usb_device::{bus::UsbBus, prelude::UsbDevice, UsbError};
 use usbd_serial::SerialPort;
 
-pub(crate) struct SerProg<'a, B>
+/// Host data moves through stack buffers of N bytes, at least 2. Boards with RAM to spare can
+/// raise it.
+pub(crate) struct SerProg<'a, B, L, const N: usize = MAX_BUFFER_SIZE>
 where
     B: UsbBus,
+    L: OutputPin,
 {
     spi_manager: SpiManager,
     serial: SerialPort<'a, B>,
     usb_dev: UsbDevice<'a, B>,
+    /// Active low status LED, lit while a command is handled and shortly after
+    led: L,
+    led_off: Option<Deadline>,
+    watchdog: IndependentWatchdog,
+    op_buf: OpBuf,
+    /// Probed by V_PROBE, or once SPI first runs with auto-probe
+    #[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+    chip: Option<Chip>,
+    /// Size set by the host in 2^n bytes, reported instead of the probed one
+    chip_size_log2: Option<u8>,
+    clocks: Clocks,
+    response_delay_us: u32,
+    /// Whether the current reply already waited out response_delay_us
+    response_delayed: bool,
+    in_command: bool,
+    #[cfg(feature = "vendor-extension")]
+    crc: Crc,
+    #[cfg(feature = "vendor-extension")]
+    crc8_mode: bool,
+    #[cfg(feature = "vendor-extension")]
+    crc8: u8,
+    /// Replies, or parts of them, that couldn't be handed to the USB stack
+    #[cfg(feature = "vendor-extension")]
+    dropped_writes: u32,
+    /// Commands dropped because they couldn't be parsed or arrived incomplete
+    #[cfg(feature = "vendor-extension")]
+    parse_errors: u32,
+    #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+    address_endian: AddressEndian,
+    /// Times an O_SPIOP read may be repeated to get two that agree, 0 trusts the first read
+    #[cfg(feature = "vendor-extension")]
+    read_retries: u8,
+    /// Access to the flash page the settings are saved in
+    #[cfg(feature = "vendor-extension")]
+    flash_writer: FlashWriter<'a>,
+    /// Holds the request to enter the ROM bootloader across the reset
+    #[cfg(feature = "bootloader-reset")]
+    backup: BackupDomain,
+    /// Message of the panic that caused the last reset, if that is what caused it
+    #[cfg(feature = "diagnostics")]
+    panic_message: Option<&'static [u8]>,
 }
 
 #[derive(Snafu, Debug)]
@@ -25,30 +98,118 @@ pub enum SerProgError {
     WriteFail,
     #[snafu(display("Could not read from serial"))]
     ReadFail,
+    #[snafu(display("Timed out waiting for the rest of the command"))]
+    Timeout,
+    #[snafu(display("Byte {:#04x} is not a known opcode", byte))]
+    UnknownOpCode { byte: u8 },
+    #[cfg(not(feature = "memory-read"))]
     #[snafu(display("OpCode {:?} is not implemented", opcode))]
     NotImplemented { opcode: OpCode },
+    #[snafu(display("Length {} is above the maximum of {}", len, max))]
+    LengthTooLarge { len: usize, max: usize },
+    #[snafu(display("SPI is not enabled, S_SPI_FREQ has to come first"))]
+    SpiNotEnabled,
+    #[snafu(display("SPI transfer failed: {}", source))]
+    SpiTransfer { source: SpiError },
+    #[snafu(display("Could not build the reply: {}", source))]
+    Response { source: DataError },
+    #[cfg(feature = "vendor-extension")]
+    #[snafu(display("Command CRC8 does not match"))]
+    CrcMismatch,
 }
 
-impl<'a, B> SerProg<'a, B>
+impl From<SpiError> for SerProgError {
+    fn from(err: SpiError) -> Self {
+        match err {
+            SpiError::NotEnabled => SerProgError::SpiNotEnabled,
+            source => SerProgError::SpiTransfer { source },
+        }
+    }
+}
+
+impl<'a, B, L, const N: usize> SerProg<'a, B, L, N>
 where
     B: UsbBus,
+    L: OutputPin,
 {
+    // The first chunk of a streamed reply leads with the Ack, with N below 2 no data would fit
+    // and the chunk loop wouldn't make progress. Checked once N is known, new() refers to it.
+    const CHUNK_SIZE_CHECK: () = assert!(N >= 2, "SerProg needs chunks of at least 2 bytes");
+
     pub fn new(
         spi_manager: SpiManager,
         serial: SerialPort<'a, B>,
         usb_dev: UsbDevice<'a, B>,
+        led: L,
+        watchdog: IndependentWatchdog,
+        clocks: Clocks,
+        #[cfg(feature = "vendor-extension")] crc: Crc,
+        #[cfg(feature = "vendor-extension")] flash_writer: FlashWriter<'a>,
+        #[cfg(feature = "bootloader-reset")] backup: BackupDomain,
     ) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CHUNK_SIZE_CHECK;
         Self {
             spi_manager,
             serial,
             usb_dev,
+            led,
+            led_off: None,
+            watchdog,
+            op_buf: OpBuf::new(),
+            #[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+            chip: None,
+            chip_size_log2: None,
+            clocks,
+            response_delay_us: RESPONSE_DELAY_US,
+            response_delayed: false,
+            in_command: false,
+            #[cfg(feature = "vendor-extension")]
+            crc,
+            #[cfg(feature = "vendor-extension")]
+            crc8_mode: false,
+            #[cfg(feature = "vendor-extension")]
+            crc8: 0,
+            #[cfg(feature = "vendor-extension")]
+            dropped_writes: 0,
+            #[cfg(feature = "vendor-extension")]
+            parse_errors: 0,
+            #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+            address_endian: AddressEndian::Little,
+            #[cfg(feature = "vendor-extension")]
+            read_retries: 0,
+            #[cfg(feature = "vendor-extension")]
+            flash_writer,
+            #[cfg(feature = "bootloader-reset")]
+            backup,
+            #[cfg(feature = "diagnostics")]
+            panic_message: panic_persist::get_panic_message_bytes(),
         }
     }
 
-    pub fn read_u8(&mut self) -> u8 {
+    pub fn read_u8(&mut self) -> Result<u8, SerProgError> {
+        let mut buf = [0u8; 1];
+        let deadline = Deadline::after_us(self.clocks.sysclk(), COMMAND_TIMEOUT_US);
         loop {
-            if let Ok(c) = Read::read(&mut self.serial) {
-                return c;
+            match self.serial.read(&mut buf) {
+                Ok(1) => {
+                    #[cfg(feature = "vendor-extension")]
+                    {
+                        self.crc8 = crc8_update(self.crc8, buf[0]);
+                    }
+                    return Ok(buf[0]);
+                }
+                // Nothing received yet, keep the USB stack running until something arrives
+                Ok(_) | Err(UsbError::WouldBlock) => (),
+                Err(_) => return Err(SerProgError::ReadFail),
+            }
+
+            self.update_led();
+            self.watchdog.feed();
+
+            // Waiting for the next opcode is fine, but a host stalling mid command has lost sync
+            if self.in_command && deadline.expired() {
+                return Err(SerProgError::Timeout);
             }
 
             if !self.usb_dev.poll(&mut [&mut self.serial]) {
@@ -57,147 +218,1307 @@ where
         }
     }
 
-    fn read_u24_as_u32(&mut self) -> u32 {
-        let mut val = self.read_u8() as u32;
-        val |= (self.read_u8() as u32) << 8;
-        val |= (self.read_u8() as u32) << 16;
-        val
+    /// Reads the opcode starting the next command
+    pub fn read_opcode(&mut self) -> Result<OpCode, SerProgError> {
+        #[cfg(feature = "vendor-extension")]
+        {
+            self.crc8 = 0;
+        }
+        self.in_command = false;
+        self.response_delayed = false;
+        let byte = self.read_u8()?;
+        self.in_command = true;
+        OpCode::from_u8(byte).ok_or(SerProgError::UnknownOpCode { byte })
+    }
+
+    /// Discards whatever the host has already sent
+    fn drain(&mut self) {
+        let mut buf = [0u8; 64];
+        loop {
+            self.usb_dev.poll(&mut [&mut self.serial]);
+            match self.serial.read(&mut buf) {
+                Ok(len) if len > 0 => (),
+                _ => break,
+            }
+        }
+    }
+
+    /// Called by handlers once all parameters are read and before acting on them. Verifies the
+    /// trailing CRC8 over the command when that mode is enabled.
+    fn end_of_command(&mut self) -> Result<(), SerProgError> {
+        #[cfg(feature = "vendor-extension")]
+        {
+            if self.crc8_mode {
+                let expected = self.crc8;
+                if self.read_u8()? != expected {
+                    return Err(SerProgError::CrcMismatch);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_u24_as_u32(&mut self) -> Result<u32, SerProgError> {
+        let mut val = self.read_u8()? as u32;
+        val |= (self.read_u8()? as u32) << 8;
+        val |= (self.read_u8()? as u32) << 16;
+        Ok(val)
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let mut val = self.read_u8() as u32;
-        val |= (self.read_u8() as u32) << 8;
-        val |= (self.read_u8() as u32) << 16;
-        val |= (self.read_u8() as u32) << 24;
-        val
+    fn read_u32(&mut self) -> Result<u32, SerProgError> {
+        let mut val = self.read_u8()? as u32;
+        val |= (self.read_u8()? as u32) << 8;
+        val |= (self.read_u8()? as u32) << 16;
+        val |= (self.read_u8()? as u32) << 24;
+        Ok(val)
     }
 
-    pub fn send_response(&mut self, buf: &[u8]) {
+    pub fn send_response(&mut self, buf: &[u8]) -> Result<(), SerProgError> {
+        let res = self.write_all(buf);
+        #[cfg(feature = "vendor-extension")]
+        if res.is_err() {
+            self.dropped_writes = self.dropped_writes.wrapping_add(1);
+        }
+        res
+    }
+
+    /// Pushes out what the USB stack still holds of the replies and waits for the host to take it,
+    /// so a short reply isn't left buffered until the next poll. usbd-serial ends a reply that
+    /// fills its last packet with a zero length packet, so the host doesn't wait for more.
+    pub fn flush(&mut self) -> Result<(), SerProgError> {
+        let deadline = Deadline::after_us(self.clocks.sysclk(), RESPONSE_TIMEOUT_US);
+        let res = loop {
+            match self.serial.flush() {
+                Ok(()) => break Ok(()),
+                Err(UsbError::WouldBlock) => {
+                    self.watchdog.feed();
+                    if deadline.expired() {
+                        break Err(SerProgError::WriteFail);
+                    }
+                    self.usb_dev.poll(&mut [&mut self.serial]);
+                }
+                Err(_) => break Err(SerProgError::WriteFail),
+            }
+        };
+        #[cfg(feature = "vendor-extension")]
+        if res.is_err() {
+            self.dropped_writes = self.dropped_writes.wrapping_add(1);
+        }
+        res
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), SerProgError> {
+        // Once per reply, not for every chunk of a streamed one
+        if !self.response_delayed {
+            self.response_delayed = true;
+            self.watchdog.feed();
+            timing::delay_us(self.clocks.sysclk(), self.response_delay_us);
+        }
+
         let mut write_offset = 0;
         let count = buf.len();
+        let mut deadline = Deadline::after_us(self.clocks.sysclk(), RESPONSE_TIMEOUT_US);
         while write_offset < count {
             match self.serial.write(&buf[write_offset..count]) {
                 Ok(len) if len > 0 => {
                     write_offset += len;
+                    deadline = Deadline::after_us(self.clocks.sysclk(), RESPONSE_TIMEOUT_US);
+                }
+                // The endpoint is full, let the host drain it
+                Ok(_) | Err(UsbError::WouldBlock) => {
+                    self.watchdog.feed();
+                    if deadline.expired() {
+                        return Err(SerProgError::WriteFail);
+                    }
+                    self.usb_dev.poll(&mut [&mut self.serial]);
                 }
-                _ => {}
+                Err(_) => return Err(SerProgError::WriteFail),
             }
         }
+
+        Ok(())
     }
 
     pub fn handle_command(
         &mut self,
         cmd: OpCode,
         mapr: &mut MAPR,
-        crl: &mut CRL,
-        apb: &mut APB2,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
     ) -> Result<ResponsePacket, SerProgError> {
-        match cmd {
+        self.set_led(true);
+        let res = match cmd {
             OpCode::Nop => self.handle_nop(),
             OpCode::QIface => self.handle_q_iface(),
             OpCode::QCmdMap => self.handle_q_cmd_map(),
             OpCode::QPgmName => self.handle_q_pgm_name(),
             OpCode::QSerBuf => self.handle_q_serbuf(),
             OpCode::QBusType => self.handle_q_bus_type(),
+            OpCode::QChipSize => self.handle_q_chip_size(),
+            OpCode::QOpBuf => self.handle_q_op_buf(),
+            OpCode::QWrnMaxLen => self.handle_q_wrn_max_len(),
+            OpCode::OInit => self.handle_o_init(),
+            OpCode::OWriteB => self.handle_o_write_b(),
+            OpCode::OWriteN => self.handle_o_write_n(),
+            OpCode::ODelay => self.handle_o_delay(),
+            OpCode::OExec => self.handle_o_exec(),
             OpCode::SyncNop => self.handle_sync_nop(),
+            OpCode::QRdnMaxLen => self.handle_q_rdn_max_len(),
             OpCode::SBusType => self.handle_s_bus_type(),
             OpCode::OSpiOp => self.handle_o_spi_op(),
-            OpCode::SSpiFreq => self.handle_s_spi_freq(mapr, crl, apb),
-            opcode => Err(SerProgError::NotImplemented { opcode }),
+            OpCode::SSpiFreq => self.handle_s_spi_freq(mapr, cr, apb),
+            OpCode::SPinState => self.handle_s_pin_state(mapr, cr, apb),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VProbe => self.handle_v_probe(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VQSpiConfig => self.handle_v_q_spi_config(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VVerifyCrc => self.handle_v_verify_crc(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VMultiRead => self.handle_v_multi_read(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSResponseDelay => self.handle_v_s_response_delay(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSCrc8Mode => self.handle_v_s_crc8_mode(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VFactoryReset => self.handle_v_factory_reset(cr),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VReadSfdp => self.handle_v_read_sfdp(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSSpiMode => self.handle_v_s_spi_mode(mapr, cr, apb),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSChipSize => self.handle_v_s_chip_size(),
+            #[cfg(feature = "bootloader-reset")]
+            OpCode::VBootloader => self.handle_v_bootloader(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSCsHighTime => self.handle_v_s_cs_high_time(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VLoopback => self.handle_v_loopback(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VQCapabilities => self.handle_v_q_capabilities(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSChipSelect => self.handle_v_s_chip_select(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VQDiagnostics => self.handle_v_q_diagnostics(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VAbort => self.handle_v_abort(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSPinSpeed => self.handle_v_s_pin_speed(mapr, cr, apb),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSCsSetupTime => self.handle_v_s_cs_setup_time(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSaveSettings => self.handle_v_save_settings(),
+            #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+            OpCode::VSAddressEndian => self.handle_v_s_address_endian(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VReadJedecId => self.handle_v_read_jedec_id(),
+            #[cfg(feature = "diagnostics")]
+            OpCode::VReadPanic => self.handle_v_read_panic(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSFrameSize => self.handle_v_s_frame_size(),
+            #[cfg(feature = "vendor-extension")]
+            OpCode::VSReadRetries => self.handle_v_s_read_retries(),
+            #[cfg(feature = "memory-read")]
+            OpCode::RByte => self.handle_r_byte(),
+            #[cfg(feature = "memory-read")]
+            OpCode::RNBytes => self.handle_r_n_bytes(),
+            #[cfg(not(feature = "memory-read"))]
+            opcode @ (OpCode::RByte | OpCode::RNBytes) => {
+                Err(SerProgError::NotImplemented { opcode })
+            }
+        };
+        // A transfer may have left it off mid blink
+        self.set_led(true);
+        self.led_off = Some(Deadline::after_us(self.clocks.sysclk(), LED_FLASH_US));
+
+        match res {
+            // The command is dropped, tell the host to resend it
+            #[cfg(feature = "vendor-extension")]
+            Err(SerProgError::CrcMismatch) => {
+                self.parse_errors = self.parse_errors.wrapping_add(1);
+                Ok(ResponsePacket::VSet {
+                    res: ResponseType::Nak,
+                })
+            }
+            res => res,
         }
     }
 
+    /// Answers a failed command so the host fails fast instead of waiting for its own timeout
+    pub fn send_error(&mut self, err: &SerProgError) {
+        #[cfg(feature = "vendor-extension")]
+        if let SerProgError::UnknownOpCode { .. }
+        | SerProgError::LengthTooLarge { .. }
+        | SerProgError::Timeout = err
+        {
+            self.parse_errors = self.parse_errors.wrapping_add(1);
+        }
+
+        let reply: &[u8] = match err {
+            // Without knowing the command or trusting its length the parameters can't be skipped,
+            // framing is lost
+            SerProgError::UnknownOpCode { .. } | SerProgError::LengthTooLarge { .. } => {
+                self.drain();
+                &[ResponseType::Nak as u8]
+            }
+            // Nudge the host into resynchronising, once what is left of the stalled command is
+            // gone so it can't be parsed as new opcodes
+            #[cfg(feature = "resync-on-timeout")]
+            SerProgError::Timeout => {
+                self.drain();
+                &[ResponseType::Nak as u8, ResponseType::Ack as u8]
+            }
+            #[cfg(not(feature = "resync-on-timeout"))]
+            SerProgError::Timeout => {
+                self.drain();
+                &[ResponseType::Nak as u8]
+            }
+            #[cfg(not(feature = "memory-read"))]
+            SerProgError::NotImplemented { .. } => &[ResponseType::Nak as u8],
+            SerProgError::SpiNotEnabled | SerProgError::SpiTransfer { .. } => {
+                &[ResponseType::Nak as u8]
+            }
+            // The command ran but its reply can't be sent whole, a short one would desync the host
+            SerProgError::Response { .. } => {
+                #[cfg(feature = "vendor-extension")]
+                {
+                    self.dropped_writes = self.dropped_writes.wrapping_add(1);
+                }
+                &[ResponseType::Nak as u8]
+            }
+            #[cfg(feature = "vendor-extension")]
+            SerProgError::CrcMismatch => &[ResponseType::Nak as u8],
+            // The link itself failed, nothing would get through
+            SerProgError::ReadFail | SerProgError::WriteFail => return,
+        };
+        self.send_response(reply).ok();
+    }
+
     fn handle_nop(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
         Ok(ResponsePacket::Nop)
     }
 
     fn handle_q_iface(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
         Ok(ResponsePacket::QIface {
             iface_version: I_FACE_VERSION,
         })
     }
 
     fn handle_q_cmd_map(&mut self) -> Result<ResponsePacket, SerProgError> {
-        let cmd_map: [u8; 32] = {
-            let mut cmd_map: [u8; 32] = [0; 32];
-            cmd_map.copy_from_slice(&CMD_MAP.to_le_bytes());
-            cmd_map
-        };
+        self.end_of_command()?;
+        let mut cmd_map = CMD_MAP;
+        // Only advertised once there is a size to report
+        if self.chip_size_log2().is_none() {
+            let n = OpCode::QChipSize as usize;
+            cmd_map[n / 8] &= !(1 << (n % 8));
+        }
 
         Ok(ResponsePacket::QCmdMap { cmd_map })
     }
 
     fn handle_q_pgm_name(&mut self) -> Result<ResponsePacket, SerProgError> {
-        let pgm_name: [u8; 16] = {
-            let mut pgm_name: [u8; 16] = [0; 16];
-            pgm_name[0..PGM_NAME.len()].copy_from_slice(PGM_NAME.as_bytes());
-            pgm_name
-        };
-
-        Ok(ResponsePacket::QPgmName { pgm_name })
+        self.end_of_command()?;
+        Ok(ResponsePacket::QPgmName {
+            pgm_name: pgm_name(),
+        })
     }
 
     fn handle_q_serbuf(&mut self) -> Result<ResponsePacket, SerProgError> {
-        // Pretend to be 64k
-        Ok(ResponsePacket::QSerBuf { size: 0xffff })
+        self.end_of_command()?;
+        // The reply only has 16 bits, advertise as much as fits
+        Ok(ResponsePacket::QSerBuf {
+            size: SER_BUF_SIZE.min(u16::MAX as usize) as u16,
+        })
     }
 
     fn handle_q_bus_type(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
         Ok(ResponsePacket::QBusType {
-            bus_type: SUPPORTED_BUS,
+            bus_type: SUPPORTED_BUS.bits(),
+        })
+    }
+
+    fn handle_q_chip_size(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(self.chip_size_packet())
+    }
+
+    /// Size set by the host, else the probed one. Unknown until either happens.
+    fn chip_size_log2(&self) -> Option<u8> {
+        #[cfg(any(feature = "vendor-extension", feature = "auto-probe"))]
+        let probed = self.chip.map(|chip| chip.size_log2);
+        #[cfg(not(any(feature = "vendor-extension", feature = "auto-probe")))]
+        let probed = None;
+        self.chip_size_log2.or(probed)
+    }
+
+    /// Probes the chip the first time SPI runs, probing needs a valid clock
+    #[cfg(feature = "auto-probe")]
+    fn auto_probe(&mut self) {
+        if self.chip.is_none() {
+            self.chip = Chip::probe(&mut self.spi_manager);
+        }
+    }
+
+    fn chip_size_packet(&self) -> ResponsePacket {
+        match self.chip_size_log2() {
+            Some(size_log2) => ResponsePacket::QChipSize {
+                res: ResponseType::Ack,
+                size_log2,
+            },
+            None => ResponsePacket::QChipSize {
+                res: ResponseType::Nak,
+                size_log2: 0,
+            },
+        }
+    }
+
+    fn handle_q_op_buf(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(ResponsePacket::QOpBuf {
+            size: OP_BUF_SIZE.min(u16::MAX as usize) as u16,
         })
     }
 
+    fn handle_q_wrn_max_len(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(ResponsePacket::QWrnMaxLen {
+            size: MAX_WRITE_N as u32,
+        })
+    }
+
+    /// Reads the 24 bit address of R_BYTE and R_NBYTES in the byte order the host picked
+    #[cfg(feature = "memory-read")]
+    fn read_address(&mut self) -> Result<u32, SerProgError> {
+        let addr = self.read_u24_as_u32()?;
+        #[cfg(feature = "vendor-extension")]
+        if self.address_endian == AddressEndian::Big {
+            return Ok(addr.swap_bytes() >> 8);
+        }
+        Ok(addr)
+    }
+
+    /// Reads the byte at a 24 bit address with a READ command
+    #[cfg(feature = "memory-read")]
+    fn handle_r_byte(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let addr = self.read_address()?;
+        self.end_of_command()?;
+
+        let mut byte = [0u8; 1];
+        flash::read(&mut self.spi_manager, addr, &mut byte)?;
+        Ok(ResponsePacket::RByte { byte: byte[0] })
+    }
+
+    /// Reads `len` bytes from a 24 bit address with READ commands. The reply is streamed, it may be
+    /// far larger than any buffer.
+    #[cfg(feature = "memory-read")]
+    fn handle_r_n_bytes(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let addr = self.read_address()?;
+        let len = check_len(self.read_u24_as_u32()? as usize, MAX_READ_N)?;
+        self.end_of_command()?;
+
+        if len == 0 {
+            self.send_response(&[ResponseType::Ack as u8])?;
+            return Ok(ResponsePacket::Streamed);
+        }
+
+        self.stream_from_spi(len, |spi_manager, offset, buf| {
+            flash::read(spi_manager, addr + offset as u32, buf)
+        })?;
+        Ok(ResponsePacket::Streamed)
+    }
+
     fn handle_sync_nop(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
         Ok(ResponsePacket::SyncNop)
     }
 
+    fn handle_q_rdn_max_len(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(ResponsePacket::QRdnMaxLen {
+            size: MAX_READ_N as u32,
+        })
+    }
+
     fn handle_s_bus_type(&mut self) -> Result<ResponsePacket, SerProgError> {
-        let res = if self.read_u8() == SUPPORTED_BUS {
-            ResponseType::Ack
-        } else {
-            ResponseType::Nak
+        // The mask lists every bus the host is fine with, such as SPI | PARALLEL. Only SPI is
+        // driven, so it has to be one of them. Reserved bits mean a host this doesn't understand.
+        let bus_type = BusType::new(self.read_u8()?);
+        self.end_of_command()?;
+        let res = match bus_type {
+            Some(bus_type) if bus_type.spi() => ResponseType::Ack,
+            _ => ResponseType::Nak,
         };
 
         Ok(ResponsePacket::SBusType { res })
     }
 
+    /// Clocks out `slen` bytes from the host then clocks in `rlen` bytes, with CS held across both.
+    /// Each phase is streamed in chunks of N bytes, so transfers are only bounded by the 24
+    /// bit length fields. Write data goes out as it arrives, so in CRC8 mode a mismatch is only
+    /// caught after the write phase.
     fn handle_o_spi_op(&mut self) -> Result<ResponsePacket, SerProgError> {
-        // TODO: Implement OSpiOp
-        Err(SerProgError::NotImplemented {
-            opcode: OpCode::OSpiOp,
+        let slen = check_len(self.read_u24_as_u32()? as usize, MAX_SPI_OP_WRITE)?;
+        let rlen = check_len(self.read_u24_as_u32()? as usize, MAX_READ_N)?;
+        #[cfg(feature = "vendor-extension")]
+        if self.read_retries > 0 && rlen > 0 && slen <= N && rlen <= N {
+            return self.spi_op_verified(slen, rlen);
+        }
+
+        let selected = self.spi_select();
+        let res = self.spi_op(slen, rlen, selected);
+        // Release the chip on every path, including the host stalling mid command
+        self.spi_unselect();
+
+        res
+    }
+
+    fn spi_op(
+        &mut self,
+        slen: usize,
+        rlen: usize,
+        mut spi_res: Result<(), SpiError>,
+    ) -> Result<ResponsePacket, SerProgError> {
+        self.stream_to_spi(slen, &mut spi_res)?;
+        self.end_of_command()?;
+
+        spi_res?;
+        if rlen == 0 {
+            self.send_response(&[ResponseType::Ack as u8])?;
+            return Ok(ResponsePacket::Streamed);
+        }
+
+        self.stream_from_spi(rlen, |spi_manager, _, buf| spi_manager.read(buf))?;
+        Ok(ResponsePacket::Streamed)
+    }
+
+    /// Runs an O_SPIOP until two reads in a row agree, for wiring noisy enough to flip bits. Each
+    /// attempt repeats the whole op, so only ops sending a known read command are retried, the
+    /// others are run once. Both phases have to fit in N bytes to be compared, longer ops never
+    /// get here. Nak if the reads still disagree after read_retries more attempts.
+    #[cfg(feature = "vendor-extension")]
+    fn spi_op_verified(
+        &mut self,
+        slen: usize,
+        rlen: usize,
+    ) -> Result<ResponsePacket, SerProgError> {
+        let mut tx = [0u8; N];
+        for b in &mut tx[..slen] {
+            *b = self.read_u8()?;
+        }
+        self.end_of_command()?;
+
+        let mut prev = [0u8; N];
+        let mut cur = [0u8; N];
+        self.spi_manager
+            .read_write(&tx[..slen], &mut prev[..rlen])?;
+        if !flash::is_read_only(&tx[..slen]) {
+            self.send_response(&[ResponseType::Ack as u8])?;
+            self.send_response(&prev[..rlen])?;
+            return Ok(ResponsePacket::Streamed);
+        }
+        for _ in 0..self.read_retries {
+            self.watchdog.feed();
+            self.spi_manager.read_write(&tx[..slen], &mut cur[..rlen])?;
+            if cur[..rlen] == prev[..rlen] {
+                self.send_response(&[ResponseType::Ack as u8])?;
+                self.send_response(&cur[..rlen])?;
+                return Ok(ResponsePacket::Streamed);
+            }
+            prev = cur;
+        }
+
+        self.send_response(&[ResponseType::Nak as u8])?;
+        Ok(ResponsePacket::Streamed)
+    }
+
+    /// Sends an Ack followed by `len` bytes that `read` fills in chunks of at most N bytes, given
+    /// the offset of each chunk. A failure before anything went out is returned for send_error()
+    /// to Nak, past that it is too late and the host sees a short reply.
+    fn stream_from_spi(
+        &mut self,
+        len: usize,
+        mut read: impl FnMut(&mut SpiManager, usize, &mut [u8]) -> Result<(), SpiError>,
+    ) -> Result<(), SerProgError> {
+        let mut data = [0u8; N];
+        // The first chunk leads with the Ack
+        data[0] = ResponseType::Ack as u8;
+        let mut start = 1;
+        let mut offset = 0;
+        while offset < len {
+            let n = chunk_len(len - offset, N - start);
+            match read(&mut self.spi_manager, offset, &mut data[start..start + n]) {
+                Ok(()) => self.send_response(&data[..start + n])?,
+                Err(source) if offset == 0 => return Err(source.into()),
+                Err(_) => return Err(SerProgError::WriteFail),
+            }
+            // Nothing else feeds the watchdog while the host keeps up with the reply
+            self.watchdog.feed();
+            self.blink_led(offset);
+            offset += n;
+            start = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Clocks out `len` bytes read from the host in chunks of N bytes. Once the SPI has
+    /// failed, or if `spi_res` was an error to begin with, the data is still drained so the stream
+    /// stays in sync. `spi_res` is left holding the first SPI error.
+    fn stream_to_spi(
+        &mut self,
+        len: usize,
+        spi_res: &mut Result<(), SpiError>,
+    ) -> Result<(), SerProgError> {
+        let mut data = [0u8; N];
+        let mut offset = 0;
+        while offset < len {
+            let n = chunk_len(len - offset, N);
+            for b in &mut data[..n] {
+                *b = self.read_u8()?;
+            }
+            if spi_res.is_ok() {
+                *spi_res = self.spi_manager.write(&data[..n]);
+            }
+            self.watchdog.feed();
+            self.blink_led(offset);
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Empties the operation buffer
+    fn handle_o_init(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        self.op_buf.clear();
+        Ok(ResponsePacket::OpBuf {
+            res: ResponseType::Ack,
         })
     }
 
+    /// Queues a byte write to a 24 bit address, see handle_o_exec()
+    fn handle_o_write_b(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let addr = self.read_u24_as_u32()?;
+        let byte = self.read_u8()?;
+        self.end_of_command()?;
+
+        let queued = self.op_buf.begin_write_b(addr, byte);
+        Ok(self.commit_op(queued))
+    }
+
+    /// Queues a write of `len` bytes to a 24 bit address, see handle_o_exec(). Writes too long
+    /// for the operation buffer are run right away, see stream_write_n().
+    fn handle_o_write_n(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let len = check_len(self.read_u24_as_u32()? as usize, MAX_WRITE_N_STREAMED)?;
+        let addr = self.read_u24_as_u32()?;
+        if len > MAX_WRITE_N {
+            return self.stream_write_n(addr, len);
+        }
+
+        let queued = len > 0 && self.op_buf.begin_write_n(addr, len);
+        for _ in 0..len {
+            // Drained even when rejected so the stream stays in sync
+            let byte = self.read_u8()?;
+            if queued {
+                self.op_buf.stage(&[byte]);
+            }
+        }
+        self.end_of_command()?;
+
+        Ok(self.commit_op(queued))
+    }
+
+    /// Programs `len` bytes as they arrive, one page program per page they touch. The ops queued
+    /// before are run first so that everything still happens in order, the ones after are queued
+    /// as usual. Data goes out as it arrives, so in CRC8 mode a mismatch is only caught after it
+    /// was programmed. Once an op has failed the data is still drained but not programmed.
+    fn stream_write_n(&mut self, addr: u32, len: usize) -> Result<ResponsePacket, SerProgError> {
+        let mut ok = self.run_ops();
+        self.op_buf.clear();
+
+        let sysclk = self.clocks.sysclk();
+        let mut page = [0u8; flash::PAGE_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            let page_addr = addr.wrapping_add(offset as u32);
+            let n = flash::page_chunk_len(page_addr, len - offset);
+            for b in &mut page[..n] {
+                *b = self.read_u8()?;
+            }
+            if ok {
+                let deadline = Deadline::after_us(sysclk, PROGRAM_TIMEOUT_US);
+                ok =
+                    flash::program(&mut self.spi_manager, page_addr, &page[..n], &deadline).is_ok();
+            }
+            self.watchdog.feed();
+            self.blink_led(offset);
+            offset += n;
+        }
+        self.end_of_command()?;
+
+        let res = if ok {
+            ResponseType::Ack
+        } else {
+            ResponseType::Nak
+        };
+        Ok(ResponsePacket::OpBuf { res })
+    }
+
+    /// Queues a delay in microseconds
+    fn handle_o_delay(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let us = self.read_u32()?;
+        self.end_of_command()?;
+
+        let queued = self.op_buf.begin_delay(us);
+        Ok(self.commit_op(queued))
+    }
+
+    /// Adds the op staged by the handler to the queue, unless it didn't fit
+    fn commit_op(&mut self, queued: bool) -> ResponsePacket {
+        let res = if queued {
+            self.op_buf.commit();
+            ResponseType::Ack
+        } else {
+            ResponseType::Nak
+        };
+        ResponsePacket::OpBuf { res }
+    }
+
+    /// Runs the queued ops in order and empties the queue. Writes are flash page programs at
+    /// their address that are waited on, split at page boundaries. The first failing op aborts
+    /// the rest with a Nak.
+    fn handle_o_exec(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+
+        let res = if self.run_ops() {
+            ResponseType::Ack
+        } else {
+            ResponseType::Nak
+        };
+        self.op_buf.clear();
+
+        Ok(ResponsePacket::OpBuf { res })
+    }
+
+    /// Runs the queued ops in order, stopping at the first one that fails. The queue is left to
+    /// the caller to empty.
+    fn run_ops(&mut self) -> bool {
+        let sysclk = self.clocks.sysclk();
+        for op in self.op_buf.ops() {
+            let deadline = Deadline::after_us(sysclk, PROGRAM_TIMEOUT_US);
+            let ok = match op {
+                Op::WriteB { addr, byte } => {
+                    flash::program(&mut self.spi_manager, addr, &[byte], &deadline).is_ok()
+                }
+                Op::WriteN { addr, data } => flash::program_pages(
+                    &mut self.spi_manager,
+                    addr,
+                    data,
+                    sysclk,
+                    PROGRAM_TIMEOUT_US,
+                )
+                .is_ok(),
+                Op::Delay { us } => {
+                    // Sliced so the watchdog stays fed through long delays
+                    let mut left = us;
+                    while left > 0 {
+                        let slice = left.min(100_000);
+                        timing::delay_us(sysclk, slice);
+                        self.watchdog.feed();
+                        left -= slice;
+                    }
+                    true
+                }
+            };
+            self.watchdog.feed();
+
+            if !ok {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn set_led(&mut self, on: bool) {
+        if on {
+            self.led.set_low().ok();
+        } else {
+            self.led.set_high().ok();
+        }
+    }
+
+    /// Turns the LED off once the flash after a command is over, without blocking
+    fn update_led(&mut self) {
+        if self.led_off.as_ref().map_or(false, Deadline::expired) {
+            self.led_off = None;
+            self.set_led(false);
+        }
+    }
+
+    /// Toggles the LED every chunk of a long transfer, so it flickers instead of staying lit
+    fn blink_led(&mut self, offset: usize) {
+        self.set_led((offset / N) % 2 == 1);
+    }
+
     fn handle_s_spi_freq(
         &mut self,
         mapr: &mut MAPR,
-        crl: &mut CRL,
-        apb: &mut APB2,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
     ) -> Result<ResponsePacket, SerProgError> {
-        // Implement SSpiFreq
-        let freq = self.read_u32();
-        if freq == 0 {
-            Ok(ResponsePacket::SSpiFreq {
+        // Clamped to the fastest clock the bus can do, the reply carries the frequency in use
+        let freq = self.read_u32()?;
+        self.end_of_command()?;
+        match self.spi_manager.configure(freq.hz(), mapr, cr, apb) {
+            Ok(set_freq) => {
+                #[cfg(feature = "auto-probe")]
+                self.auto_probe();
+                Ok(ResponsePacket::SSpiFreq {
+                    res: ResponseType::Ack,
+                    set_freq: set_freq.0,
+                })
+            }
+            // Zero, the previous configuration is still in place
+            Err(_) => Ok(ResponsePacket::SSpiFreq {
                 res: ResponseType::Nak,
                 set_freq: 0,
-            })
+            }),
+        }
+    }
+
+    /// Drives the SPI pins, or releases all of them including CS to let another master use the
+    /// bus. S_SPI_FREQ is still accepted while released and applies once the pins are driven.
+    fn handle_s_pin_state(
+        &mut self,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<ResponsePacket, SerProgError> {
+        let drive = self.read_u8()? != 0;
+        self.end_of_command()?;
+
+        let res = if drive {
+            match self.spi_manager.drive(mapr, cr, apb) {
+                Ok(()) => {
+                    // An S_SPI_FREQ made while released couldn't probe
+                    #[cfg(feature = "auto-probe")]
+                    self.auto_probe();
+                    ResponseType::Ack
+                }
+                Err(_) => ResponseType::Nak,
+            }
         } else {
-            self.spi_manager.configure(freq.hz(), mapr, crl, apb);
-            Ok(ResponsePacket::SSpiFreq {
-                res: ResponseType::Ack,
-                set_freq: freq,
-            })
+            self.spi_manager.tristate(cr);
+            ResponseType::Ack
+        };
+
+        Ok(ResponsePacket::SPinState { res })
+    }
+
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_probe(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        self.chip = Chip::probe(&mut self.spi_manager);
+        Ok(self.chip_size_packet())
+    }
+
+    /// Replies with the raw JEDEC ID, for tools identifying the chip themselves. Unlike
+    /// VProbe nothing is checked or remembered, and it is NAKed while SPI is disabled.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_read_jedec_id(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        let id = flash::read_jedec_id(&mut self.spi_manager)?;
+        Ok(ResponsePacket::VJedecId { id })
+    }
+
+    /// Sends an Ack, the 16 bit length of the message of the panic that caused the last reset,
+    /// then the message. The length is 0 when the last reset wasn't a panic.
+    #[cfg(feature = "diagnostics")]
+    fn handle_v_read_panic(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        let (header, message) = panic_reply(self.panic_message.unwrap_or(&[]));
+        self.send_response(&header)?;
+        self.send_response(message)?;
+        Ok(ResponsePacket::Streamed)
+    }
+
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_q_spi_config(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(ResponsePacket::VQSpiConfig {
+            dummy_byte: crate::spi::DUMMY_BYTE,
+            io_modes: crate::spi::SUPPORTED_IO,
+            spi_modes: crate::spi::SUPPORTED_MODES,
+            cs_active_high: crate::spi::CS_ACTIVE_HIGH,
+        })
+    }
+
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_q_capabilities(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(ResponsePacket::VQCapabilities {
+            capabilities: Capabilities {
+                spi_modes: crate::spi::SUPPORTED_MODES,
+                io_modes: crate::spi::SUPPORTED_IO,
+                hardware_cs: crate::spi::HARDWARE_CS,
+                max_freq: self.spi_manager.freq_range().max.0,
+                version: firmware_version(),
+            },
+        })
+    }
+
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_q_diagnostics(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        Ok(ResponsePacket::VQDiagnostics {
+            dropped_writes: self.dropped_writes,
+            parse_errors: self.parse_errors,
+        })
+    }
+
+    /// Drops whatever a killed host left behind, the queued ops and any bytes still in flight, and
+    /// answers like S_SYNCNOP. The chip is deselected in case a transfer was cut short. Nothing
+    /// after the opcode is read, a CRC8 included, as the host may have lost track of the mode.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_abort(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.op_buf.clear();
+        self.spi_manager.deselect();
+        self.drain();
+        Ok(ResponsePacket::SyncNop)
+    }
+
+    /// Checks the wiring with MOSI jumpered to MISO. A pattern is clocked out with CS high, so
+    /// the chip stays off the bus, and has to come back unchanged. SPI must be enabled first. A
+    /// failed transfer is a Nak with offset 0xFF, past the end of the pattern.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_loopback(&mut self) -> Result<ResponsePacket, SerProgError> {
+        // Both levels, alternating bits and a walking one
+        const PATTERN: [u8; 12] = [
+            0x00, 0xFF, 0xAA, 0x55, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80,
+        ];
+
+        self.end_of_command()?;
+
+        let mut buf = PATTERN;
+        // The reply has its own Nak frame, a lone Nak from send_error() would desync the host
+        if self.spi_manager.exchange(&mut buf).is_err() {
+            return Ok(ResponsePacket::VLoopback {
+                res: ResponseType::Nak,
+                offset: 0xFF,
+            });
+        }
+        Ok(
+            match PATTERN.iter().zip(&buf).position(|(tx, rx)| tx != rx) {
+                None => ResponsePacket::VLoopback {
+                    res: ResponseType::Ack,
+                    offset: 0,
+                },
+                Some(offset) => ResponsePacket::VLoopback {
+                    res: ResponseType::Nak,
+                    offset: offset as u8,
+                },
+            },
+        )
+    }
+
+    /// Reads a region and compares it against a host supplied CRC without sending the data back.
+    /// The CRC is the one computed by the STM32 CRC unit (CRC-32/MPEG-2) over little endian
+    /// words, with a trailing partial word zero padded. A failed read is a Nak with the CRC of
+    /// what was read before it.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_verify_crc(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let addr = self.read_u24_as_u32()?;
+        let len = self.read_u24_as_u32()? as usize;
+        let expected = self.read_u32()?;
+        self.end_of_command()?;
+
+        let mut chunk = [0u8; N];
+        let mut offset = 0;
+        self.crc.reset();
+        let mut read_ok = true;
+        while offset < len {
+            let n = chunk_len(len - offset, N);
+            // The reply has its own Nak frame, a lone Nak from send_error() would desync the host
+            if flash::read(&mut self.spi_manager, addr + offset as u32, &mut chunk[..n]).is_err() {
+                read_ok = false;
+                break;
+            }
+            // Nothing goes over USB until the whole region is read
+            self.watchdog.feed();
+
+            for word in chunk[..n].chunks(4) {
+                let mut bytes = [0u8; 4];
+                bytes[..word.len()].copy_from_slice(word);
+                self.crc.write(u32::from_le_bytes(bytes));
+            }
+            offset += n;
+        }
+
+        let crc = self.crc.read();
+        let res = if read_ok && crc == expected {
+            ResponseType::Ack
+        } else {
+            ResponseType::Nak
+        };
+
+        Ok(ResponsePacket::VVerifyCrc { res, crc })
+    }
+
+    /// Reads a list of up to MAX_READ_REGIONS (address, length) regions of at most N bytes each.
+    /// The command is Acked, then every region follows in order as an Ack and its data, or a lone
+    /// Nak if that region couldn't be read.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_multi_read(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let count = self.read_u8()? as usize;
+        let mut regions = [(0u32, 0usize); MAX_READ_REGIONS];
+        let mut valid = count > 0 && count <= MAX_READ_REGIONS;
+        for i in 0..count {
+            let addr = self.read_u24_as_u32()?;
+            let len = self.read_u24_as_u32()? as usize;
+            // Drain every pair even when rejecting the command so the stream stays in sync
+            match regions.get_mut(i) {
+                Some(region) if len <= N => *region = (addr, len),
+                _ => valid = false,
+            }
+        }
+        self.end_of_command()?;
+
+        if !valid {
+            self.send_response(&[ResponseType::Nak as u8])?;
+            return Ok(ResponsePacket::Streamed);
+        }
+
+        self.send_response(&[ResponseType::Ack as u8])?;
+        let mut data = [0u8; N];
+        for &(addr, len) in &regions[..count] {
+            match flash::read(&mut self.spi_manager, addr, &mut data[..len]) {
+                Ok(()) => {
+                    self.send_response(&[ResponseType::Ack as u8])?;
+                    self.send_response(&data[..len])?;
+                }
+                Err(_) => self.send_response(&[ResponseType::Nak as u8])?,
+            }
         }
+
+        Ok(ResponsePacket::Streamed)
+    }
+
+    /// Sets the delay in microseconds inserted before every response, capped at
+    /// MAX_RESPONSE_DELAY_US
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_response_delay(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let delay_us = self.read_u32()?;
+        self.end_of_command()?;
+
+        self.response_delay_us = delay_us.min(MAX_RESPONSE_DELAY_US);
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
     }
 
-    fn spi_select(&mut self) {
-        // TODO
-        // self.spi_cs.set_low().unwrap();
+    /// Sets the byte order of R_BYTE and R_NBYTES addresses, 0 for little endian as the spec has
+    /// it and 1 for big endian
+    #[cfg(all(feature = "memory-read", feature = "vendor-extension"))]
+    fn handle_v_s_address_endian(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let endian = self.read_u8()?;
+        self.end_of_command()?;
+
+        let res = match endian {
+            0 => {
+                self.address_endian = AddressEndian::Little;
+                ResponseType::Ack
+            }
+            1 => {
+                self.address_endian = AddressEndian::Big;
+                ResponseType::Ack
+            }
+            _ => ResponseType::Nak,
+        };
+        Ok(ResponsePacket::VSet { res })
+    }
+
+    /// Saves the SPI frequency and mode to flash, they are restored at the next boot
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_save_settings(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+
+        let settings = Settings {
+            freq: self.spi_manager.freq().map(|freq| freq.0),
+            mode: self.spi_manager.mode(),
+        };
+        let res = match settings.store(&mut self.flash_writer) {
+            Ok(()) => ResponseType::Ack,
+            Err(_) => ResponseType::Nak,
+        };
+        Ok(ResponsePacket::VSet { res })
+    }
+
+    /// Applies the settings saved with the vendor command, as if the host had sent them. Done
+    /// before the first command is read.
+    #[cfg(feature = "vendor-extension")]
+    pub fn restore_settings(&mut self, mapr: &mut MAPR, cr: &mut SpiCr, apb: &mut SpiApb) {
+        let settings = match Settings::load(&self.flash_writer) {
+            Some(settings) => settings,
+            None => return,
+        };
+
+        self.spi_manager.set_mode(settings.mode, mapr, cr, apb).ok();
+        if let Some(freq) = settings.freq {
+            if self.spi_manager.configure(freq.hz(), mapr, cr, apb).is_ok() {
+                #[cfg(feature = "auto-probe")]
+                self.auto_probe();
+            }
+        }
+    }
+
+    /// Sets the time in nanoseconds between asserting CS and the first clock edge
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_cs_setup_time(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let ns = self.read_u32()?;
+        self.end_of_command()?;
+
+        self.spi_manager.set_cs_setup_ns(ns);
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
+    }
+
+    /// Sets the minimum time in nanoseconds CS stays high between two transfers
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_cs_high_time(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let ns = self.read_u32()?;
+        self.end_of_command()?;
+
+        self.spi_manager.set_cs_high_ns(ns);
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
+    }
+
+    /// Picks which of the chip select lines later transfers assert, NAKs a line that doesn't exist
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_chip_select(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let index = self.read_u8()?;
+        self.end_of_command()?;
+
+        let res = match self.spi_manager.set_cs_index(index as usize) {
+            Ok(()) => ResponseType::Ack,
+            Err(_) => ResponseType::Nak,
+        };
+        Ok(ResponsePacket::VSet { res })
+    }
+
+    /// Enables or disables the trailing CRC8 after every command. The command enabling it is
+    /// itself checked against the mode it was sent in.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_crc8_mode(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let enable = self.read_u8()? != 0;
+        self.end_of_command()?;
+
+        self.crc8_mode = enable;
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
+    }
+
+    /// Sets the slew rate of SCK and MOSI, 0 for 2 MHz, 1 for 10 MHz and 2 for 50 MHz. Slower edges
+    /// cut ringing and EMI on short wires but smear the bits at high SCK frequencies.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_pin_speed(
+        &mut self,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<ResponsePacket, SerProgError> {
+        let speed = self.read_u8()?;
+        self.end_of_command()?;
+
+        let speed = match speed {
+            0 => PinSpeed::Mhz2,
+            1 => PinSpeed::Mhz10,
+            2 => PinSpeed::Mhz50,
+            _ => {
+                return Ok(ResponsePacket::VSet {
+                    res: ResponseType::Nak,
+                })
+            }
+        };
+        let res = match self.spi_manager.set_pin_speed(speed, mapr, cr, apb) {
+            Ok(()) => ResponseType::Ack,
+            Err(_) => ResponseType::Nak,
+        };
+        Ok(ResponsePacket::VSet { res })
+    }
+
+    /// Sets the frame size of the hardware SPI to 8 or 16 bits, which doesn't change the bytes on
+    /// the wire. 16 bit frames cut the per frame overhead of long transfers.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_frame_size(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let bits = self.read_u8()?;
+        self.end_of_command()?;
+
+        let frame_size = match bits {
+            8 => FrameSize::Bits8,
+            16 => FrameSize::Bits16,
+            _ => {
+                return Ok(ResponsePacket::VSet {
+                    res: ResponseType::Nak,
+                })
+            }
+        };
+        self.spi_manager.set_frame_size(frame_size);
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
+    }
+
+    /// Sets how many times an O_SPIOP read may be repeated to get two that agree, 0 to trust the
+    /// first read. See spi_op_verified().
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_read_retries(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let retries = self.read_u8()?;
+        self.end_of_command()?;
+
+        self.read_retries = retries;
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
+    }
+
+    /// Drops everything negotiated since power on without touching the USB connection, and zeroes
+    /// the V_Q_DIAGNOSTICS counters. SPI is disabled until the next S_SPI_FREQ, which also probes
+    /// the chip again with auto-probe.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_factory_reset(&mut self, cr: &mut SpiCr) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+
+        self.spi_manager.reset(cr);
+        self.chip = None;
+        self.chip_size_log2 = None;
+        self.op_buf.clear();
+        self.response_delay_us = RESPONSE_DELAY_US;
+        self.crc8_mode = false;
+        self.read_retries = 0;
+        self.dropped_writes = 0;
+        self.parse_errors = 0;
+        #[cfg(feature = "memory-read")]
+        {
+            self.address_endian = AddressEndian::Little;
+        }
+        Ok(ResponsePacket::VSet {
+            res: ResponseType::Ack,
+        })
+    }
+
+    /// Dumps `len` bytes of the raw SFDP table starting at `addr`. The reply is an Ack followed by
+    /// the data, or a lone Nak if nothing could be read.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_read_sfdp(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let addr = self.read_u24_as_u32()?;
+        let len = self.read_u24_as_u32()? as usize;
+        self.end_of_command()?;
+
+        if len == 0 {
+            self.send_response(&[ResponseType::Nak as u8])?;
+            return Ok(ResponsePacket::Streamed);
+        }
+
+        self.stream_from_spi(len, |spi_manager, offset, buf| {
+            flash::read_sfdp(spi_manager, addr + offset as u32, buf)
+        })?;
+        Ok(ResponsePacket::Streamed)
+    }
+
+    /// Sets the SPI mode, 0 to 3 with CPOL in bit 1 and CPHA in bit 0. It applies immediately and
+    /// is kept across S_SPI_FREQ.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_spi_mode(
+        &mut self,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<ResponsePacket, SerProgError> {
+        let mode = self.read_u8()?;
+        self.end_of_command()?;
+
+        let mode = match mode_from_u8(mode) {
+            Some(mode) => mode,
+            None => {
+                return Ok(ResponsePacket::VSet {
+                    res: ResponseType::Nak,
+                })
+            }
+        };
+
+        let res = match self.spi_manager.set_mode(mode, mapr, cr, apb) {
+            Ok(()) => ResponseType::Ack,
+            Err(_) => ResponseType::Nak,
+        };
+        Ok(ResponsePacket::VSet { res })
+    }
+
+    /// Sets the chip size reported by Q_CHIPSIZE in 2^n bytes, for buses where it can't be
+    /// probed. 0 goes back to the probed size.
+    #[cfg(feature = "vendor-extension")]
+    fn handle_v_s_chip_size(&mut self) -> Result<ResponsePacket, SerProgError> {
+        let size_log2 = self.read_u8()?;
+        self.end_of_command()?;
+
+        let res = match size_log2 {
+            0 => {
+                self.chip_size_log2 = None;
+                ResponseType::Ack
+            }
+            1..=31 => {
+                self.chip_size_log2 = Some(size_log2);
+                ResponseType::Ack
+            }
+            _ => ResponseType::Nak,
+        };
+        Ok(ResponsePacket::VSet { res })
+    }
+
+    /// Acks, then resets into the ROM bootloader, see enter_bootloader_if_requested() in main
+    #[cfg(feature = "bootloader-reset")]
+    fn handle_v_bootloader(&mut self) -> Result<ResponsePacket, SerProgError> {
+        self.end_of_command()?;
+        self.send_response(&[ResponseType::Ack as u8])?;
+
+        // The Ack has only reached the host once the IN endpoint is empty
+        self.flush().ok();
+
+        self.spi_manager.deselect();
+        self.backup
+            .write_data_register_low(BOOTLOADER_MAGIC_REGISTER, BOOTLOADER_MAGIC);
+        SCB::sys_reset()
+    }
+
+    fn spi_select(&mut self) -> Result<(), SpiError> {
+        self.spi_manager.select()
     }
 
     fn spi_unselect(&mut self) {
-        // TODO
-        // self.spi_cs.set_high().unwrap();
+        self.spi_manager.deselect();
+    }
+}
+
+/// Length of the next chunk of a transfer with `left` bytes to go, at most `max`. Only the last
+/// chunk is odd, so that 16 bit frames don't drop to 8 bits for a lone byte mid transfer.
+fn chunk_len(left: usize, max: usize) -> usize {
+    if left <= max {
+        left
+    } else {
+        (max & !1).max(1)
+    }
+}
+
+/// Rejects a length field above `max`, most likely a corrupted byte. Waiting for that much data
+/// would stall until the command times out.
+fn check_len(len: usize, max: usize) -> Result<usize, SerProgError> {
+    if len > max {
+        return Err(SerProgError::LengthTooLarge { len, max });
     }
+    Ok(len)
 }
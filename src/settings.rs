@@ -0,0 +1,124 @@
+use crate::{
+    data_utils::crc8_update,
+    spi::{mode_from_u8, mode_to_u8},
+};
+use embedded_hal::spi::Mode;
+use stm32f1xx_hal::flash::{self, FlashWriter};
+
+// Last 1K page of the 64K flash, the layouts in memory/ keep the firmware out of it
+const SETTINGS_OFFSET: u32 = 0xFC00;
+// Erased flash reads as 0xFF, so nothing is restored until the first save
+const SETTINGS_MAGIC: u16 = 0x5350;
+
+/// SPI configuration saved by the host and restored at boot, so scripts driving the dongle don't
+/// have to negotiate it every session
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Settings {
+    /// Requested SCK frequency, None leaves SPI disabled until S_SPI_FREQ
+    pub(crate) freq: Option<u32>,
+    pub(crate) mode: Mode,
+}
+
+impl Settings {
+    const SIZE: usize = 8;
+
+    /// Magic, SPI mode number, CRC8 over the other bytes, then the frequency with 0 for none. A
+    /// whole number of halfwords, flash is programmed 16 bits at a time.
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..2].copy_from_slice(&SETTINGS_MAGIC.to_le_bytes());
+        bytes[2] = mode_to_u8(self.mode);
+        bytes[4..8].copy_from_slice(&self.freq.unwrap_or(0).to_le_bytes());
+        bytes[3] = checksum(&bytes);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE
+            || bytes[0..2] != SETTINGS_MAGIC.to_le_bytes()
+            || bytes[3] != checksum(bytes)
+        {
+            return None;
+        }
+
+        let freq = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        Some(Self {
+            freq: if freq == 0 { None } else { Some(freq) },
+            mode: mode_from_u8(bytes[2])?,
+        })
+    }
+
+    /// The saved settings, None if nothing valid was ever saved
+    pub(crate) fn load(writer: &FlashWriter) -> Option<Self> {
+        let bytes = writer.read(SETTINGS_OFFSET, Self::SIZE).ok()?;
+        Self::from_bytes(bytes)
+    }
+
+    /// Saves self, unless it is what is already stored. The page only takes so many erase cycles.
+    pub(crate) fn store(&self, writer: &mut FlashWriter) -> Result<(), flash::Error> {
+        if Self::load(writer) == Some(*self) {
+            return Ok(());
+        }
+
+        writer.page_erase(SETTINGS_OFFSET)?;
+        writer.write(SETTINGS_OFFSET, &self.to_bytes())
+    }
+}
+
+/// CRC8 over the serialized settings, skipping the byte that holds it
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 3)
+        .fold(0, |crc, (_, &b)| crc8_update(crc, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_settings() -> impl Iterator<Item = Settings> {
+        (0..4).flat_map(|n| {
+            let mode = mode_from_u8(n).unwrap();
+            [None, Some(1), Some(1_000_000), Some(u32::MAX)]
+                .iter()
+                .map(move |&freq| Settings { freq, mode })
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        for settings in all_settings() {
+            assert!(Settings::from_bytes(&settings.to_bytes()) == Some(settings));
+        }
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        for settings in all_settings() {
+            let mut bytes = settings.to_bytes();
+            bytes[1] ^= 0x01;
+            assert!(Settings::from_bytes(&bytes).is_none());
+        }
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        for settings in all_settings() {
+            let mut bytes = settings.to_bytes();
+            bytes[3] ^= 0x01;
+            assert!(Settings::from_bytes(&bytes).is_none());
+
+            // A flipped payload bit no longer matches the stored CRC8 either
+            let mut bytes = settings.to_bytes();
+            bytes[5] ^= 0x10;
+            assert!(Settings::from_bytes(&bytes).is_none());
+        }
+    }
+
+    #[test]
+    fn erased_page_is_rejected() {
+        assert!(Settings::from_bytes(&[0xFF; Settings::SIZE]).is_none());
+    }
+}
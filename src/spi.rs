@@ -1,36 +1,490 @@
-use embedded_hal::spi::{Mode, Phase, Polarity};
+use crate::timing::{self, Deadline};
+use cortex_m::asm::delay;
+use embedded_hal::{
+    blocking,
+    digital::v2::{InputPin, OutputPin},
+    spi::{FullDuplex, Mode, Phase, Polarity},
+};
+use snafu::Snafu;
+use stm32f1xx_hal::pac;
 use stm32f1xx_hal::{
     afio::MAPR,
-    gpio::gpioa::{CRL, PA4, PA5, PA6, PA7},
-    gpio::{Alternate, Floating, Input, Output, PushPull},
-    pac::SPI1,
-    rcc::{Clocks, APB2},
-    spi::{Spi, Spi1NoRemap},
+    gpio::{Alternate, Floating, IOPinSpeed, Input, Output, OutputSpeed, PushPull, State},
+    rcc::Clocks,
+    spi::Spi,
     time::Hertz,
 };
 
+// The bus is SPI1 on PA4 to PA7, the spi2 feature moves it to SPI2 on PB12 to PB15 for boards
+// that route the flash there. The extra chip selects sit on free pins of the same CR register,
+// and are only claimed when vendor-extension can select them.
+#[cfg(all(feature = "vendor-extension", not(feature = "spi2")))]
+pub(crate) use stm32f1xx_hal::gpio::gpioa::{PA2 as Cs2Pin, PA3 as Cs1Pin};
+#[cfg(all(feature = "vendor-extension", feature = "spi2"))]
+pub(crate) use stm32f1xx_hal::gpio::gpiob::{PB10 as Cs2Pin, PB11 as Cs1Pin};
+#[cfg(not(feature = "spi2"))]
+pub(crate) use stm32f1xx_hal::{
+    gpio::gpioa::{CRL as SpiCr, PA4 as CsPin, PA5 as SckPin, PA6 as MisoPin, PA7 as MosiPin},
+    pac::SPI1 as SpiPeriph,
+    rcc::APB2 as SpiApb,
+    spi::Spi1NoRemap as SpiRemap,
+};
+#[cfg(feature = "spi2")]
+pub(crate) use stm32f1xx_hal::{
+    gpio::gpiob::{CRH as SpiCr, PB12 as CsPin, PB13 as SckPin, PB14 as MisoPin, PB15 as MosiPin},
+    pac::SPI2 as SpiPeriph,
+    rcc::APB1 as SpiApb,
+    spi::Spi2NoRemap as SpiRemap,
+};
+
+// Power on default, the host can change it with a vendor command
 const SPI_MODE: Mode = Mode {
     polarity: Polarity::IdleLow,
     phase: Phase::CaptureOnFirstTransition,
 };
 
+// Power on slew rate of SCK and MOSI, the fastest edges for clean signals at the top SCK
+// frequencies. The host can change it with a vendor command.
+const PIN_SPEED: PinSpeed = PinSpeed::Mhz50;
+
+// Largest divider of the bus clock the hardware prescaler has
+const SPI_MAX_PRESCALER: u32 = 256;
+
+// Slowest SCK, bit-banged for long cables and isolators. A byte takes 8 ms at this rate, so the
+// bit-banged transfers feed the watchdog themselves.
+const SPI_MIN_FREQ: Hertz = Hertz(1_000);
+
+// Longest a single frame may take on the hardware SPI, far above 16 bits at the slowest prescaler
+const SPI_FRAME_TIMEOUT_US: u32 = 1_000;
+
+// Bytes write() stages at a time to exchange them in place. Even, so that only the last chunk of a
+// transfer in 16 bit frames can end with an odd byte.
+const WRITE_CHUNK_SIZE: usize = 32;
+
+// Data line configurations reported to the host, bit 1 is dual and bit 2 quad
+#[cfg(feature = "vendor-extension")]
+pub(crate) const IO_SINGLE: u8 = 1 << 0;
+/// The SPI peripheral on the STM32F1 only has a single MOSI and MISO line, so dual and quad
+/// transfers can't be done and must be refused rather than silently clocked as single
+#[cfg(feature = "vendor-extension")]
+pub(crate) const SUPPORTED_IO: u8 = IO_SINGLE;
+
+// SPI modes reported to the host, bit n is mode n. The bit-banged fallback honours all four too.
+#[cfg(feature = "vendor-extension")]
+pub(crate) const SUPPORTED_MODES: u8 = 0b1111;
+// Chip select is asserted by driving it low
+#[cfg(feature = "vendor-extension")]
+pub(crate) const CS_ACTIVE_HIGH: bool = false;
+// CS is a GPIO toggled by the firmware, not the peripheral's NSS output
+#[cfg(feature = "vendor-extension")]
+pub(crate) const HARDWARE_CS: bool = false;
+
+// Longest CS setup or high time the host may set. Datasheets ask for nanoseconds, and a
+// transaction waits these out several times between watchdog feeds.
+#[cfg(feature = "vendor-extension")]
+const MAX_CS_DELAY_NS: u32 = 1_000_000;
+
+/// Number of chip select lines, for several chips sharing the bus
+#[cfg(feature = "vendor-extension")]
+pub(crate) const CS_COUNT: usize = 3;
+
+/// Slew rate of the driven SCK and MOSI pins. 50 MHz keeps edges sharp at 24 MHz SCK over long
+/// wires, the slower settings ring and radiate less on short runs at low SCK frequencies. Those
+/// are picked by the host through a vendor command.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PinSpeed {
+    #[cfg(feature = "vendor-extension")]
+    Mhz2,
+    #[cfg(feature = "vendor-extension")]
+    Mhz10,
+    Mhz50,
+}
+
+impl From<PinSpeed> for IOPinSpeed {
+    fn from(speed: PinSpeed) -> Self {
+        match speed {
+            #[cfg(feature = "vendor-extension")]
+            PinSpeed::Mhz2 => IOPinSpeed::Mhz2,
+            #[cfg(feature = "vendor-extension")]
+            PinSpeed::Mhz10 => IOPinSpeed::Mhz10,
+            PinSpeed::Mhz50 => IOPinSpeed::Mhz50,
+        }
+    }
+}
+
+/// Data frame size of the hardware SPI. 16 bit frames halve the per frame overhead of long
+/// transfers, the bytes on the wire are the same either way.
+#[cfg(feature = "vendor-extension")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FrameSize {
+    Bits8,
+    Bits16,
+}
+
+// Pulled input, CNF 0b10 with MODE 0b00, and the ODR value picking the direction as a BSRR write
+// for PA6. MISO is the 7th pin of its CR register on SPI2 as well, PB14 in CRH.
+#[cfg(any(feature = "miso-pull-up", feature = "miso-pull-down"))]
+const MISO_CR_PULL: u32 = 0b1000 << 24;
+#[cfg(feature = "miso-pull-up")]
+const MISO_PULL_BSRR: u32 = 1 << 6;
+#[cfg(feature = "miso-pull-down")]
+const MISO_PULL_BSRR: u32 = 1 << (16 + 6);
+
+// SCK is the 6th pin of its CR register too, PA5 in CRL or PB13 in CRH. CNF1 tells an alternate
+// function output from a general purpose one, MODE keeps the speed either way.
+#[cfg(feature = "vendor-extension")]
+const SCK_CR_CNF1: u32 = 1 << (5 * 4 + 3);
+#[cfg(all(feature = "vendor-extension", not(feature = "spi2")))]
+const SCK_ODR_BIT: u32 = 5;
+#[cfg(all(feature = "vendor-extension", feature = "spi2"))]
+const SCK_ODR_BIT: u32 = 13;
+
+/// Lowest and highest SCK frequency, both reachable
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SpiFreqRange {
+    pub(crate) min: Hertz,
+    pub(crate) max: Hertz,
+}
+
+impl SpiFreqRange {
+    /// Range of an SPI peripheral on a bus clocked at `pclk`, with the core clocked at `sysclk`
+    /// timing the bit-banged fallback below the prescaler
+    pub(crate) fn new(pclk: Hertz, sysclk: Hertz) -> Self {
+        SpiFreqRange {
+            // Bit-banged, with the half period rounded to whole core clock cycles
+            min: bitbang_freq(sysclk, half_period_cycles(sysclk, SPI_MIN_FREQ)),
+            max: prescaled_freq(pclk, pclk),
+        }
+    }
+
+    pub(crate) fn clamp(&self, freq: Hertz) -> Hertz {
+        Hertz(freq.0.max(self.min.0).min(self.max.0))
+    }
+
+    /// Clamps `freq` into the range, as serprog asks for the fastest clock not above the request.
+    /// The HAL can't derive a prescaler above the bus clock and panics, so nothing higher may
+    /// reach it. Zero is the only frequency rejected.
+    pub(crate) fn check(&self, freq: Hertz) -> Result<Hertz, SpiError> {
+        if freq.0 == 0 {
+            return Err(SpiError::InvalidFrequency { freq: freq.0 });
+        }
+        Ok(self.clamp(freq))
+    }
+}
+
+/// Fastest clock the prescaler can produce from `pclk` that isn't above `freq`. The prescaler
+/// divides the bus clock by a power of two from 2 to SPI_MAX_PRESCALER. The HAL rounds to the
+/// nearest divider instead, so it is handed the exact result.
+fn prescaled_freq(pclk: Hertz, freq: Hertz) -> Hertz {
+    let div = ((pclk.0 + freq.0 - 1) / freq.0)
+        .next_power_of_two()
+        .max(2)
+        .min(SPI_MAX_PRESCALER);
+    Hertz(pclk.0 / div)
+}
+
+/// Half of an SCK period at `freq` in core clock cycles
+fn half_period_cycles(sysclk: Hertz, freq: Hertz) -> u32 {
+    (sysclk.0 / (2 * freq.0)).max(1)
+}
+
+/// Frequency a bit-banged half period of `half_period` core clock cycles gives. An upper bound,
+/// the GPIO accesses make every bit slightly longer.
+fn bitbang_freq(sysclk: Hertz, half_period: u32) -> Hertz {
+    Hertz(sysclk.0 / (2 * half_period))
+}
+
+/// SPI mode from its number, CPOL in bit 1 and CPHA in bit 0
+#[cfg(feature = "vendor-extension")]
+pub(crate) fn mode_from_u8(n: u8) -> Option<Mode> {
+    if n > 3 {
+        return None;
+    }
+    Some(Mode {
+        polarity: if n & 0b10 != 0 {
+            Polarity::IdleHigh
+        } else {
+            Polarity::IdleLow
+        },
+        phase: if n & 0b01 != 0 {
+            Phase::CaptureOnSecondTransition
+        } else {
+            Phase::CaptureOnFirstTransition
+        },
+    })
+}
+
+#[cfg(feature = "vendor-extension")]
+pub(crate) fn mode_to_u8(mode: Mode) -> u8 {
+    let cpol = (mode.polarity == Polarity::IdleHigh) as u8;
+    let cpha = (mode.phase == Phase::CaptureOnSecondTransition) as u8;
+    (cpol << 1) | cpha
+}
+
+/// Value clocked out on MOSI while reading, when the chip doesn't care about it
+pub(crate) const DUMMY_BYTE: u8 = 0x00;
+
+#[derive(Snafu, Debug)]
+pub enum SpiError {
+    #[snafu(display("SPI is not enabled"))]
+    NotEnabled,
+    #[snafu(display("SPI transfer failed"))]
+    Transfer,
+    #[snafu(display("SPI transfer timed out"))]
+    Timeout,
+    #[snafu(display("SPI frequency {} Hz is out of range", freq))]
+    InvalidFrequency { freq: u32 },
+    #[cfg(feature = "vendor-extension")]
+    #[snafu(display("No chip select line {}", index))]
+    InvalidChipSelect { index: usize },
+}
+
 type SpiPins = (
-    PA5<Alternate<PushPull>>, // sck
-    PA6<Input<Floating>>,     // miso
-    PA7<Alternate<PushPull>>, // mosi
+    SckPin<Alternate<PushPull>>,  // sck
+    MisoPin<Input<Floating>>,     // miso
+    MosiPin<Alternate<PushPull>>, // mosi
+);
+
+/// Chip select lines in the order the host numbers them, line 0 is the power on default
+#[cfg(feature = "vendor-extension")]
+pub(crate) type CsPins = (
+    CsPin<Input<Floating>>,
+    Cs1Pin<Input<Floating>>,
+    Cs2Pin<Input<Floating>>,
 );
+/// Only line 0 exists without vendor-extension, there is no opcode to pick another
+#[cfg(not(feature = "vendor-extension"))]
+pub(crate) type CsPins = (CsPin<Input<Floating>>,);
 
+/// Every chip select line. Only one is ever driven low, the others keep their chips off the bus.
+struct ChipSelects<MODE> {
+    cs0: CsPin<MODE>,
+    #[cfg(feature = "vendor-extension")]
+    cs1: Cs1Pin<MODE>,
+    #[cfg(feature = "vendor-extension")]
+    cs2: Cs2Pin<MODE>,
+}
+
+impl ChipSelects<Input<Floating>> {
+    /// Deselects every chip, without glitching low on the mode change
+    fn into_deselected(self, cr: &mut SpiCr) -> ChipSelects<Output<PushPull>> {
+        ChipSelects {
+            cs0: self.cs0.into_push_pull_output_with_state(cr, State::High),
+            #[cfg(feature = "vendor-extension")]
+            cs1: self.cs1.into_push_pull_output_with_state(cr, State::High),
+            #[cfg(feature = "vendor-extension")]
+            cs2: self.cs2.into_push_pull_output_with_state(cr, State::High),
+        }
+    }
+}
+
+impl ChipSelects<Output<PushPull>> {
+    fn into_floating_input(self, cr: &mut SpiCr) -> ChipSelects<Input<Floating>> {
+        ChipSelects {
+            cs0: self.cs0.into_floating_input(cr),
+            #[cfg(feature = "vendor-extension")]
+            cs1: self.cs1.into_floating_input(cr),
+            #[cfg(feature = "vendor-extension")]
+            cs2: self.cs2.into_floating_input(cr),
+        }
+    }
+
+    /// Asserts line `index`, which SpiManager keeps in range
+    fn select(&mut self, index: usize) {
+        match index {
+            #[cfg(feature = "vendor-extension")]
+            1 => self.cs1.set_low(),
+            #[cfg(feature = "vendor-extension")]
+            2 => self.cs2.set_low(),
+            _ => self.cs0.set_low(),
+        }
+        .ok();
+    }
+
+    /// Releases line `index`, the others were never asserted
+    fn deselect(&mut self, index: usize) {
+        match index {
+            #[cfg(feature = "vendor-extension")]
+            1 => self.cs1.set_high(),
+            #[cfg(feature = "vendor-extension")]
+            2 => self.cs2.set_high(),
+            _ => self.cs0.set_high(),
+        }
+        .ok();
+    }
+}
+
+/// Chip select is active low. It is a push-pull output held high from SpiManager::new onwards,
+/// in every state, so the chip is never selected by a floating pin.
 struct SpiDisabled {
-    cs: PA4<Input<Floating>>,
-    sck: PA5<Input<Floating>>,
-    miso: PA6<Input<Floating>>,
-    mosi: PA7<Input<Floating>>,
-    spi: SPI1,
+    cs: ChipSelects<Output<PushPull>>,
+    sck: SckPin<Input<Floating>>,
+    miso: MisoPin<Input<Floating>>,
+    mosi: MosiPin<Input<Floating>>,
+    spi: SpiPeriph,
+}
+
+/// Every pin floating, the host released the bus for another master
+struct SpiTristated {
+    cs: ChipSelects<Input<Floating>>,
+    sck: SckPin<Input<Floating>>,
+    miso: MisoPin<Input<Floating>>,
+    mosi: MosiPin<Input<Floating>>,
+    spi: SpiPeriph,
 }
 
 struct SpiEnabled {
-    cs: PA4<Output<PushPull>>,
-    spi: Spi<SPI1, Spi1NoRemap, SpiPins, u8>,
+    cs: ChipSelects<Output<PushPull>>,
+    spi: FramedSpi,
+}
+
+/// The peripheral in either frame size, the HAL tracks it in the type
+enum FramedSpi {
+    Bits8(Spi<SpiPeriph, SpiRemap, SpiPins, u8>),
+    #[cfg(feature = "vendor-extension")]
+    Bits16(Spi<SpiPeriph, SpiRemap, SpiPins, u16>),
+}
+
+impl FramedSpi {
+    #[cfg(feature = "vendor-extension")]
+    fn with_frame_size(self, frame_size: FrameSize) -> Self {
+        match (self, frame_size) {
+            (FramedSpi::Bits8(spi), FrameSize::Bits16) => FramedSpi::Bits16(spi.frame_size_16bit()),
+            (FramedSpi::Bits16(spi), FrameSize::Bits8) => FramedSpi::Bits8(spi.frame_size_8bit()),
+            (spi, _) => spi,
+        }
+    }
+
+    fn release(self) -> (SpiPeriph, SpiPins) {
+        match self {
+            FramedSpi::Bits8(spi) => spi.release(),
+            #[cfg(feature = "vendor-extension")]
+            FramedSpi::Bits16(spi) => spi.release(),
+        }
+    }
+}
+
+impl SpiEnabled {
+    #[cfg(feature = "vendor-extension")]
+    fn with_frame_size(self, frame_size: FrameSize) -> Self {
+        Self {
+            cs: self.cs,
+            spi: self.spi.with_frame_size(frame_size),
+        }
+    }
+
+    /// Exchanges `buf` in place. 16 bit frames carry the bytes in pairs, the first one as the most
+    /// significant byte since SPI shifts MSB first, so the bytes on the wire don't change.
+    fn exchange(&mut self, buf: &mut [u8], sysclk: Hertz) -> Result<(), SpiError> {
+        match &mut self.spi {
+            FramedSpi::Bits8(spi) => buf.iter_mut().try_for_each(|b| {
+                *b = Self::transfer_frame(spi, *b, sysclk)?;
+                Ok(())
+            }),
+            // SpiManager::exchange_hw() only hands over even lengths
+            #[cfg(feature = "vendor-extension")]
+            FramedSpi::Bits16(spi) => buf.chunks_exact_mut(2).try_for_each(|pair| {
+                let out = u16::from_be_bytes([pair[0], pair[1]]);
+                let word = Self::transfer_frame(spi, out, sysclk)?;
+                pair.copy_from_slice(&word.to_be_bytes());
+                Ok(())
+            }),
+        }
+    }
+
+    /// Exchanges a frame, giving up if the peripheral doesn't make progress on a dead bus
+    fn transfer_frame<W: Copy, E>(
+        spi: &mut impl FullDuplex<W, Error = E>,
+        out: W,
+        sysclk: Hertz,
+    ) -> Result<W, SpiError> {
+        let deadline = Deadline::after_us(sysclk, SPI_FRAME_TIMEOUT_US);
+        Self::poll(&deadline, || spi.send(out))?;
+        Self::poll(&deadline, || spi.read())
+    }
+
+    fn poll<T, E>(
+        deadline: &Deadline,
+        mut f: impl FnMut() -> nb::Result<T, E>,
+    ) -> Result<T, SpiError> {
+        loop {
+            match f() {
+                Ok(val) => return Ok(val),
+                Err(nb::Error::Other(_)) => return Err(SpiError::Transfer),
+                Err(nb::Error::WouldBlock) if deadline.expired() => return Err(SpiError::Timeout),
+                Err(nb::Error::WouldBlock) => (),
+            }
+        }
+    }
+}
+
+/// GPIO driven SPI for frequencies below what the prescaler can reach
+struct SpiBitBang {
+    cs: ChipSelects<Output<PushPull>>,
+    sck: SckPin<Output<PushPull>>,
+    miso: MisoPin<Input<Floating>>,
+    mosi: MosiPin<Output<PushPull>>,
+    spi: SpiPeriph,
+    mode: Mode,
+    /// Half of an SCK period in core clock cycles
+    half_period: u32,
+}
+
+impl SpiBitBang {
+    fn transfer_byte(&mut self, out: u8) -> u8 {
+        // A chunk between two of SerProg's feeds can outlast the watchdog at the slowest SCK
+        feed_watchdog();
+        let mut input = 0;
+        for bit in (0..8).rev() {
+            match self.mode.phase {
+                // Data is set up while SCK idles and sampled on the leading edge
+                Phase::CaptureOnFirstTransition => {
+                    self.set_mosi(out & (1 << bit) != 0);
+                    delay(self.half_period);
+                    self.set_sck(true);
+                    input |= self.sample_miso() << bit;
+                    delay(self.half_period);
+                    self.set_sck(false);
+                }
+                // Data is shifted out on the leading edge and sampled on the trailing one
+                Phase::CaptureOnSecondTransition => {
+                    self.set_sck(true);
+                    self.set_mosi(out & (1 << bit) != 0);
+                    delay(self.half_period);
+                    self.set_sck(false);
+                    input |= self.sample_miso() << bit;
+                    delay(self.half_period);
+                }
+            }
+        }
+        input
+    }
+
+    fn set_sck(&mut self, active: bool) {
+        if active == (self.mode.polarity == Polarity::IdleLow) {
+            self.sck.set_high().ok();
+        } else {
+            self.sck.set_low().ok();
+        }
+    }
+
+    fn set_mosi(&mut self, high: bool) {
+        if high {
+            self.mosi.set_high().ok();
+        } else {
+            self.mosi.set_low().ok();
+        }
+    }
+
+    fn sample_miso(&self) -> u8 {
+        self.miso.is_high().unwrap_or(false) as u8
+    }
+}
+
+/// Reloads the independent watchdog. SerProg owns it, but writing the reload key has no other
+/// effect, so doing it from here can't disturb its configuration.
+fn feed_watchdog() {
+    unsafe { (*pac::IWDG::ptr()).kr.write(|w| w.key().reset()) };
 }
 
 pub(crate) struct SpiManager {
@@ -44,48 +498,158 @@ pub(crate) struct SpiManager {
     */
     disabled: Option<SpiDisabled>,
     enabled: Option<SpiEnabled>,
+    bitbang: Option<SpiBitBang>,
+    tristated: Option<SpiTristated>,
     clocks: Clocks,
+    /// Kept across reconfiguration, applied whenever SPI is enabled
+    mode: Mode,
+    /// Frequency requested by the last successful enable, None while disabled. While tristated
+    /// it is the frequency to come back with.
+    freq: Option<Hertz>,
+    /// Minimum time CS stays high after a transfer, for chips that need a CS deselect time
+    /// longer than the next command takes to arrive
+    cs_high_ns: u32,
+    /// Time between asserting CS and the first SCK edge, for slow EEPROMs with a CS setup time
+    cs_setup_ns: u32,
+    /// Chip select line asserted by select()
+    cs_index: usize,
+    pin_speed: PinSpeed,
+    /// Applied whenever SPI is enabled, the bit-banged fallback doesn't have frames
+    #[cfg(feature = "vendor-extension")]
+    frame_size: FrameSize,
 }
 
 impl SpiManager {
     pub(crate) fn new(
-        cs: PA4<Input<Floating>>,
-        sck: PA5<Input<Floating>>,
-        miso: PA6<Input<Floating>>,
-        mosi: PA7<Input<Floating>>,
-        spi: SPI1,
+        cs: CsPins,
+        sck: SckPin<Input<Floating>>,
+        miso: MisoPin<Input<Floating>>,
+        mosi: MosiPin<Input<Floating>>,
+        spi: SpiPeriph,
         clocks: Clocks,
+        cr: &mut SpiCr,
     ) -> Self {
         Self {
             enabled: None,
+            bitbang: None,
+            tristated: None,
             disabled: Some(SpiDisabled {
-                cs,
+                // Deselected from the start
+                cs: ChipSelects {
+                    cs0: cs.0,
+                    #[cfg(feature = "vendor-extension")]
+                    cs1: cs.1,
+                    #[cfg(feature = "vendor-extension")]
+                    cs2: cs.2,
+                }
+                .into_deselected(cr),
                 sck,
-                miso,
+                miso: Self::pull_miso(miso, cr),
                 mosi,
                 spi,
             }),
             clocks,
+            mode: SPI_MODE,
+            freq: None,
+            cs_high_ns: 0,
+            cs_setup_ns: 0,
+            cs_index: 0,
+            pin_speed: PIN_SPEED,
+            #[cfg(feature = "vendor-extension")]
+            frame_size: FrameSize::Bits8,
         }
     }
 
-    pub(crate) fn disable(&mut self, crl: &mut CRL) {
+    /// Sets up the pull picked by the miso-pull-up or miso-pull-down feature. The HAL only takes a
+    /// floating MISO for SPI, so the pin keeps that type and the pull is configured behind its
+    /// back. Reapplied whenever the peripheral hands MISO back, so the pull holds in every state.
+    #[cfg(any(feature = "miso-pull-up", feature = "miso-pull-down"))]
+    fn pull_miso(miso: MisoPin<Input<Floating>>, _cr: &mut SpiCr) -> MisoPin<Input<Floating>> {
+        // Owning the CR register means nothing else is reconfiguring the port's pins meanwhile
+        #[cfg(not(feature = "spi2"))]
+        unsafe {
+            let gpio = &*pac::GPIOA::ptr();
+            gpio.bsrr.write(|w| w.bits(MISO_PULL_BSRR));
+            gpio.crl
+                .modify(|r, w| w.bits((r.bits() & !(0xf << 24)) | MISO_CR_PULL));
+        }
+        #[cfg(feature = "spi2")]
+        unsafe {
+            let gpio = &*pac::GPIOB::ptr();
+            gpio.bsrr.write(|w| w.bits(MISO_PULL_BSRR << 8));
+            gpio.crh
+                .modify(|r, w| w.bits((r.bits() & !(0xf << 24)) | MISO_CR_PULL));
+        }
+        miso
+    }
+
+    #[cfg(not(any(feature = "miso-pull-up", feature = "miso-pull-down")))]
+    fn pull_miso(miso: MisoPin<Input<Floating>>, _cr: &mut SpiCr) -> MisoPin<Input<Floating>> {
+        miso
+    }
+
+    pub(crate) fn disable(&mut self, cr: &mut SpiCr) {
+        self.freq = None;
         if let Some(SpiEnabled { cs, spi }) = self.enabled.take() {
             let (spi, (sck, miso, mosi)) = spi.release();
             self.disabled = Some(SpiDisabled {
-                cs: cs.into_floating_input(crl),
-                sck: sck.into_floating_input(crl),
-                miso: miso.into_floating_input(crl),
-                mosi: mosi.into_floating_input(crl),
+                cs,
+                sck: sck.into_floating_input(cr),
+                // Still the floating input type, reconfiguring it would drop the pull
+                miso: Self::pull_miso(miso, cr),
+                mosi: mosi.into_floating_input(cr),
+                spi,
+            });
+        }
+
+        if let Some(SpiBitBang {
+            cs,
+            sck,
+            miso,
+            mosi,
+            spi,
+            ..
+        }) = self.bitbang.take()
+        {
+            self.disabled = Some(SpiDisabled {
+                cs,
+                sck: sck.into_floating_input(cr),
+                miso,
+                mosi: mosi.into_floating_input(cr),
                 spi,
             });
         }
     }
 
-    pub(crate) fn enable<F>(&mut self, freq: F, mapr: &mut MAPR, crl: &mut CRL, apb: &mut APB2)
+    /// Clock of the APB bus the peripheral hangs off, which its prescaler divides
+    #[cfg(not(feature = "spi2"))]
+    fn pclk(&self) -> Hertz {
+        self.clocks.pclk2()
+    }
+
+    #[cfg(feature = "spi2")]
+    fn pclk(&self) -> Hertz {
+        self.clocks.pclk1()
+    }
+
+    /// SCK frequencies the bus can run at
+    pub(crate) fn freq_range(&self) -> SpiFreqRange {
+        SpiFreqRange::new(self.pclk(), self.clocks.sysclk())
+    }
+
+    /// Enables SPI and returns the frequency it runs at. Frequencies below what the prescaler can
+    /// reach fall back to bit-banging the pins. An invalid frequency leaves self untouched.
+    pub(crate) fn enable<F>(
+        &mut self,
+        freq: F,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<Hertz, SpiError>
     where
         F: Into<Hertz>,
     {
+        let freq = self.freq_range().check(freq.into())?;
         if let Some(SpiDisabled {
             cs,
             sck,
@@ -94,64 +658,468 @@ impl SpiManager {
             spi,
         }) = self.disabled.take()
         {
-            let pins = (
-                sck.into_alternate_push_pull(crl),
-                miso,
-                mosi.into_alternate_push_pull(crl),
-            );
-            let spi = Spi::spi1(spi, pins, mapr, SPI_MODE, freq, self.clocks, apb);
-            self.enabled = Some(SpiEnabled {
-                cs: cs.into_push_pull_output(crl),
+            if let Some(half_period) = self.bitbang_half_period(freq) {
+                let mut sck = sck.into_push_pull_output(cr);
+                let mut mosi = mosi.into_push_pull_output(cr);
+                sck.set_speed(cr, self.pin_speed.into());
+                mosi.set_speed(cr, self.pin_speed.into());
+                let mut bitbang = SpiBitBang {
+                    cs,
+                    sck,
+                    miso,
+                    mosi,
+                    spi,
+                    mode: self.mode,
+                    half_period,
+                };
+                bitbang.set_sck(false);
+                self.bitbang = Some(bitbang);
+                self.freq = Some(freq);
+
+                return Ok(self.achievable_freq(freq));
+            }
+
+            let mut sck = sck.into_alternate_push_pull(cr);
+            let mut mosi = mosi.into_alternate_push_pull(cr);
+            sck.set_speed(cr, self.pin_speed.into());
+            mosi.set_speed(cr, self.pin_speed.into());
+            let pins = (sck, miso, mosi);
+            let set_freq = prescaled_freq(self.pclk(), freq);
+            let spi = FramedSpi::Bits8(Self::new_spi(
                 spi,
-            });
+                pins,
+                self.mode,
+                set_freq,
+                self.clocks,
+                mapr,
+                apb,
+            ));
+            #[cfg(feature = "vendor-extension")]
+            let spi = spi.with_frame_size(self.frame_size);
+
+            // SCK is only driven to its CPOL idle level once the peripheral is enabled. Give it a
+            // couple of SCK periods to settle so the chip doesn't sample a spurious first edge
+            // when CS is asserted.
+            delay(2 * (self.clocks.sysclk().0 / set_freq.0));
+
+            self.enabled = Some(SpiEnabled { cs, spi });
+            self.freq = Some(freq);
+            return Ok(set_freq);
+        }
+
+        Ok(freq)
+    }
+
+    /// Half of an SCK period in core clock cycles if `freq` is below what the prescaler can reach
+    /// and has to be bit-banged
+    fn bitbang_half_period(&self, freq: Hertz) -> Option<u32> {
+        if freq.0 < self.pclk().0 / SPI_MAX_PRESCALER {
+            Some(half_period_cycles(self.clocks.sysclk(), freq))
+        } else {
+            None
         }
     }
 
-    /// Configures the SPI frequency if self is enabled, else it will be equivalent to enable()
-    pub(crate) fn configure<F>(&mut self, freq: F, mapr: &mut MAPR, crl: &mut CRL, apb: &mut APB2)
+    /// Frequency SPI runs at once enabled with `freq`
+    fn achievable_freq(&self, freq: Hertz) -> Hertz {
+        match self.bitbang_half_period(freq) {
+            Some(half_period) => bitbang_freq(self.clocks.sysclk(), half_period),
+            None => prescaled_freq(self.pclk(), freq),
+        }
+    }
+
+    #[cfg(not(feature = "spi2"))]
+    fn new_spi(
+        spi: SpiPeriph,
+        pins: SpiPins,
+        mode: Mode,
+        freq: Hertz,
+        clocks: Clocks,
+        mapr: &mut MAPR,
+        apb: &mut SpiApb,
+    ) -> Spi<SpiPeriph, SpiRemap, SpiPins, u8> {
+        Spi::spi1(spi, pins, mapr, mode, freq, clocks, apb)
+    }
+
+    /// SPI2 can't be remapped, so it has no use for MAPR
+    #[cfg(feature = "spi2")]
+    fn new_spi(
+        spi: SpiPeriph,
+        pins: SpiPins,
+        mode: Mode,
+        freq: Hertz,
+        clocks: Clocks,
+        _mapr: &mut MAPR,
+        apb: &mut SpiApb,
+    ) -> Spi<SpiPeriph, SpiRemap, SpiPins, u8> {
+        Spi::spi2(spi, pins, mode, freq, clocks, apb)
+    }
+
+    /// Configures the SPI frequency if self is enabled, else it will be equivalent to enable().
+    /// An invalid frequency is rejected before anything is torn down, so the previous
+    /// configuration keeps running.
+    pub(crate) fn configure<F>(
+        &mut self,
+        freq: F,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<Hertz, SpiError>
     where
         F: Into<Hertz>,
     {
-        // TODO: Implement configure
-        /*
-        match self.enabled.take() {
-            Some(SpiEnabled { cs, spi }) => {
-            }
-            None => {
-                self.enabled = self.
+        let freq = self.freq_range().check(freq.into())?;
+        if self.tristated.is_some() {
+            // Applied once the pins are driven again
+            self.freq = Some(freq);
+            return Ok(self.achievable_freq(freq));
+        }
+        self.disable(cr);
+        self.enable(freq, mapr, cr, apb)
+    }
+
+    /// Releases every pin, CS included, to a floating input. The frequency is remembered and SPI
+    /// comes back with it in drive().
+    pub(crate) fn tristate(&mut self, cr: &mut SpiCr) {
+        let freq = self.freq;
+        self.disable(cr);
+        self.freq = freq;
+
+        if let Some(SpiDisabled {
+            cs,
+            sck,
+            miso,
+            mosi,
+            spi,
+        }) = self.disabled.take()
+        {
+            self.tristated = Some(SpiTristated {
+                cs: cs.into_floating_input(cr),
+                sck,
+                miso,
+                mosi,
+                spi,
+            });
+        }
+    }
+
+    /// Drives the pins again with the chip deselected, re-enabling SPI if it was running
+    pub(crate) fn drive(
+        &mut self,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<(), SpiError> {
+        self.untristate(cr);
+        if let Some(freq) = self.freq {
+            self.enable(freq, mapr, cr, apb)?;
+        }
+        Ok(())
+    }
+
+    fn untristate(&mut self, cr: &mut SpiCr) {
+        if let Some(SpiTristated {
+            cs,
+            sck,
+            miso,
+            mosi,
+            spi,
+        }) = self.tristated.take()
+        {
+            self.disabled = Some(SpiDisabled {
+                cs: cs.into_deselected(cr),
+                sck,
+                miso,
+                mosi,
+                spi,
+            });
+        }
+    }
+
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Frequency last asked for, rather than the one SCK runs at. None while disabled.
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn freq(&self) -> Option<Hertz> {
+        self.freq
+    }
+
+    /// Changes the SPI mode, rebuilding the bus at the same frequency if it is enabled
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn set_mode(
+        &mut self,
+        mode: Mode,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<(), SpiError> {
+        self.mode = mode;
+        if let Some(freq) = self.freq {
+            self.configure(freq, mapr, cr, apb)?;
+        }
+        Ok(())
+    }
+
+    /// Disables SPI and restores the power on mode, with the pins driven
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn reset(&mut self, cr: &mut SpiCr) {
+        self.disable(cr);
+        self.untristate(cr);
+        self.freq = None;
+        self.mode = SPI_MODE;
+        self.cs_high_ns = 0;
+        self.cs_setup_ns = 0;
+        self.cs_index = 0;
+        self.pin_speed = PIN_SPEED;
+        self.frame_size = FrameSize::Bits8;
+    }
+
+    /// Changes the slew rate of SCK and MOSI, rebuilding the bus at the same frequency if it is
+    /// enabled
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn set_pin_speed(
+        &mut self,
+        speed: PinSpeed,
+        mapr: &mut MAPR,
+        cr: &mut SpiCr,
+        apb: &mut SpiApb,
+    ) -> Result<(), SpiError> {
+        self.pin_speed = speed;
+        if let Some(freq) = self.freq {
+            self.configure(freq, mapr, cr, apb)?;
+        }
+        Ok(())
+    }
+
+    /// Picks the frame size of the hardware SPI, applied right away if it is enabled
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn set_frame_size(&mut self, frame_size: FrameSize) {
+        self.frame_size = frame_size;
+        self.enabled = self
+            .enabled
+            .take()
+            .map(|enabled| enabled.with_frame_size(frame_size));
+    }
+
+    /// Picks the chip select line later transfers assert
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn set_cs_index(&mut self, index: usize) -> Result<(), SpiError> {
+        if index >= CS_COUNT {
+            return Err(SpiError::InvalidChipSelect { index });
+        }
+        self.cs_index = index;
+        Ok(())
+    }
+
+    /// Sets how long CS is held high after every transfer, 0 to release it right away. Capped at
+    /// MAX_CS_DELAY_NS.
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn set_cs_high_ns(&mut self, ns: u32) {
+        self.cs_high_ns = ns.min(MAX_CS_DELAY_NS);
+    }
+
+    /// Sets how long to wait after asserting CS before clocking, 0 to start right away. Capped at
+    /// MAX_CS_DELAY_NS.
+    #[cfg(feature = "vendor-extension")]
+    pub(crate) fn set_cs_setup_ns(&mut self, ns: u32) {
+        self.cs_setup_ns = ns.min(MAX_CS_DELAY_NS);
+    }
+
+    /// Asserts CS and waits out the CS setup time, for transfers made of several write() and
+    /// read() calls
+    pub(crate) fn select(&mut self) -> Result<(), SpiError> {
+        if let Some(bitbang) = self.bitbang.as_mut() {
+            bitbang.cs.select(self.cs_index);
+        } else {
+            let enabled = self.enabled.as_mut().ok_or(SpiError::NotEnabled)?;
+            enabled.cs.select(self.cs_index);
+        }
+        timing::delay_ns(self.clocks.sysclk(), self.cs_setup_ns);
+        Ok(())
+    }
+
+    /// Releases CS and waits out the minimum CS high time, does nothing if SPI is disabled
+    pub(crate) fn deselect(&mut self) {
+        if let Some(bitbang) = self.bitbang.as_mut() {
+            bitbang.cs.deselect(self.cs_index);
+        } else if let Some(enabled) = self.enabled.as_mut() {
+            enabled.cs.deselect(self.cs_index);
+        } else {
+            return;
+        }
+        timing::delay_ns(self.clocks.sysclk(), self.cs_high_ns);
+    }
+
+    /// Clocks out `tx`, discarding what comes back
+    pub(crate) fn write(&mut self, tx: &[u8]) -> Result<(), SpiError> {
+        if let Some(bitbang) = self.bitbang.as_mut() {
+            for &b in tx {
+                bitbang.transfer_byte(b);
             }
+            return Ok(());
         }
-        match self.spi_hal.take() {
-            None => {
-                self.enable(freq, mapr, crl, apb);
+
+        let mut chunk = [0u8; WRITE_CHUNK_SIZE];
+        tx.chunks(WRITE_CHUNK_SIZE).try_for_each(|part| {
+            let chunk = &mut chunk[..part.len()];
+            chunk.copy_from_slice(part);
+            self.exchange_hw(chunk)
+        })
+    }
+
+    /// Clocks out `buf` and replaces every byte with the one clocked in at the same time. CS is
+    /// left alone, so without select() the chip keeps MISO released.
+    pub(crate) fn exchange(&mut self, buf: &mut [u8]) -> Result<(), SpiError> {
+        if let Some(bitbang) = self.bitbang.as_mut() {
+            for b in buf.iter_mut() {
+                *b = bitbang.transfer_byte(*b);
             }
-            Some(spi_hal) => {
-                let (spi1, pins) = spi_hal.release();
-                self.spi_hal = Some(Spi::spi1(
-                    spi1,
-                    pins,
-                    mapr,
-                    SPI_MODE,
-                    freq,
-                    self.clocks,
-                    apb,
-                ));
+            return Ok(());
+        }
+
+        self.exchange_hw(buf)
+    }
+
+    /// Clocks in `rx.len()` bytes, dummy bytes are clocked out meanwhile
+    pub(crate) fn read(&mut self, rx: &mut [u8]) -> Result<(), SpiError> {
+        if let Some(bitbang) = self.bitbang.as_mut() {
+            for b in rx.iter_mut() {
+                *b = bitbang.transfer_byte(DUMMY_BYTE);
             }
+            return Ok(());
         }
-            match self {
-                Self::SpiDisabled { .. } => {
-                    //
-                },
-                Self::SpiEnabled { spi, cs, clocks } => {
-                    let (spi1, pins) = spi.release();
-                    core::mem::replace(self, Self::SpiEnabled {
-                        cs: *cs, spi: Spi::spi1(spi1, pins, mapr, SPI_MODE, freq, *clocks, apb), clocks: *clocks
-                    });
-                    /*
-
-                    */
-                }
+
+        rx.fill(DUMMY_BYTE);
+        self.exchange_hw(rx)
+    }
+
+    /// Exchanges `buf` in place on the hardware SPI. An odd trailing byte of a transfer in 16 bit
+    /// frames goes out in an 8 bit frame of its own. SerProg streams in even chunks, so that only
+    /// happens at the end of a phase with an odd length.
+    fn exchange_hw(&mut self, buf: &mut [u8]) -> Result<(), SpiError> {
+        let sysclk = self.clocks.sysclk();
+        let enabled = self.enabled.as_mut().ok_or(SpiError::NotEnabled)?;
+        #[cfg(feature = "vendor-extension")]
+        if matches!(enabled.spi, FramedSpi::Bits16(_)) && buf.len() % 2 == 1 {
+            let (pairs, last) = buf.split_at_mut(buf.len() - 1);
+            enabled.exchange(pairs, sysclk)?;
+
+            // The frame size only changes with the peripheral disabled, CS stays asserted
+            let mut enabled = self.enabled.take().ok_or(SpiError::NotEnabled)?;
+            self.hold_sck(true);
+            enabled = enabled.with_frame_size(FrameSize::Bits8);
+            self.hold_sck(false);
+            let res = enabled.exchange(last, sysclk);
+            self.hold_sck(true);
+            self.enabled = Some(enabled.with_frame_size(FrameSize::Bits16));
+            self.hold_sck(false);
+            return res;
+        }
+        enabled.exchange(buf, sysclk)
+    }
+
+    /// Drives SCK at its idle level from the GPIO, or hands it back to the peripheral. The
+    /// peripheral stops driving SCK while it is disabled, and with CS asserted the chip could take
+    /// a floating clock for an edge. Both drive the idle level at the handovers, so there is no
+    /// glitch either way.
+    #[cfg(feature = "vendor-extension")]
+    fn hold_sck(&self, hold: bool) {
+        let cr_bits = |bits: u32| {
+            if hold {
+                bits & !SCK_CR_CNF1
+            } else {
+                bits | SCK_CR_CNF1
             }
-        */
+        };
+        let odr = if self.mode.polarity == Polarity::IdleHigh {
+            1 << SCK_ODR_BIT
+        } else {
+            1 << (16 + SCK_ODR_BIT)
+        };
+        // The SPI manager owns the pin, nothing else reconfigures it meanwhile
+        #[cfg(not(feature = "spi2"))]
+        unsafe {
+            let gpio = &*pac::GPIOA::ptr();
+            gpio.bsrr.write(|w| w.bits(odr));
+            gpio.crl.modify(|r, w| w.bits(cr_bits(r.bits())));
+        }
+        #[cfg(feature = "spi2")]
+        unsafe {
+            let gpio = &*pac::GPIOB::ptr();
+            gpio.bsrr.write(|w| w.bits(odr));
+            gpio.crh.modify(|r, w| w.bits(cr_bits(r.bits())));
+        }
+    }
+
+    /// Selects the chip, clocks out `tx` then clocks in `rx.len()` bytes before deselecting it
+    pub(crate) fn read_write(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), SpiError> {
+        self.select()?;
+        let res = self.write(tx).and_then(|_| self.read(rx));
+        self.deselect();
+
+        res
+    }
+}
+
+/// Lets drivers written against embedded-hal run on the bus, whether it is the peripheral or the
+/// bit-banged fallback. CS is left to the caller, see SpiManager::select().
+impl blocking::spi::Transfer<u8> for SpiManager {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        self.exchange(words)?;
+        Ok(words)
+    }
+}
+
+impl blocking::spi::Write<u8> for SpiManager {
+    type Error = SpiError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        SpiManager::write(self, words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SPI1 on the Blue Pill clock tree, the bus and the core both at 72 MHz
+    const RANGE_PCLK: Hertz = Hertz(72_000_000);
+    const RANGE_SYSCLK: Hertz = Hertz(72_000_000);
+
+    fn checked(freq: u32) -> u32 {
+        SpiFreqRange::new(RANGE_PCLK, RANGE_SYSCLK)
+            .check(Hertz(freq))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn freq_above_max_is_clamped_to_max() {
+        assert_eq!(checked(100_000_000), 36_000_000);
+        assert_eq!(checked(u32::MAX), 36_000_000);
+    }
+
+    #[test]
+    fn freq_below_min_is_clamped_to_min() {
+        assert_eq!(checked(1), 1_000);
+        assert_eq!(checked(999), 1_000);
+    }
+
+    #[test]
+    fn freq_in_range_is_kept() {
+        assert_eq!(checked(36_000_000), 36_000_000);
+        assert_eq!(checked(1_000), 1_000);
+        assert_eq!(checked(18_000_000), 18_000_000);
+    }
+
+    #[test]
+    fn zero_freq_is_rejected() {
+        let range = SpiFreqRange::new(RANGE_PCLK, RANGE_SYSCLK);
+        assert!(range.check(Hertz(0)).is_err());
     }
 }
@@ -0,0 +1,41 @@
+use cortex_m::{asm::delay, peripheral::DWT};
+use stm32f1xx_hal::time::Hertz;
+
+/// Busy waits for at least `us` microseconds with the core running at `sysclk`
+pub(crate) fn delay_us(sysclk: Hertz, us: u32) {
+    if us > 0 {
+        delay((sysclk.0 / 1_000_000).saturating_mul(us));
+    }
+}
+
+/// Busy waits for at least `ns` nanoseconds with the core running at `sysclk`
+pub(crate) fn delay_ns(sysclk: Hertz, ns: u32) {
+    if ns > 0 {
+        delay(ns_to_cycles(sysclk, ns));
+    }
+}
+
+/// Core clock cycles covering `ns` nanoseconds, rounded up so a delay is never short
+fn ns_to_cycles(sysclk: Hertz, ns: u32) -> u32 {
+    let cycles = (sysclk.0 as u64 * ns as u64 + 999_999_999) / 1_000_000_000;
+    cycles.min(u32::MAX as u64) as u32
+}
+
+/// Point in time measured with the DWT cycle counter, which must be enabled at startup
+pub(crate) struct Deadline {
+    start: u32,
+    cycles: u32,
+}
+
+impl Deadline {
+    pub(crate) fn after_us(sysclk: Hertz, us: u32) -> Self {
+        Self {
+            start: DWT::get_cycle_count(),
+            cycles: (sysclk.0 / 1_000_000).saturating_mul(us),
+        }
+    }
+
+    pub(crate) fn expired(&self) -> bool {
+        DWT::get_cycle_count().wrapping_sub(self.start) >= self.cycles
+    }
+}
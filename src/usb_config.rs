@@ -0,0 +1,55 @@
+use usb_device::{
+    bus::{UsbBus, UsbBusAllocator},
+    prelude::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+};
+use usbd_serial::USB_CLASS_CDC;
+
+/// USB identity of the programmer and the knobs of the device the stack leaves open. Rebranded
+/// builds only need to change the default here.
+///
+/// The data endpoints are bulk, which have no polling interval at full speed, and usbd-serial
+/// fixes their packets at 64 bytes, the full speed maximum. Round trips are bound by how often the
+/// host schedules the bulk transfers, not by anything set here.
+pub(crate) struct UsbConfig {
+    pub(crate) vid: u16,
+    pub(crate) pid: u16,
+    pub(crate) manufacturer: &'static str,
+    pub(crate) product: &'static str,
+    /// Packet size of the control endpoint, 8, 16, 32 or 64, the builder panics on anything else.
+    /// 64 takes descriptors and line coding requests in a single packet, where the usb-device
+    /// default of 8 splits them into many. Hosts learn the size from the first 8 bytes of the
+    /// device descriptor, so every valid size enumerates, but 8 is the one to fall back to for a
+    /// host that mishandles larger ones.
+    pub(crate) max_packet_size_0: u8,
+}
+
+impl Default for UsbConfig {
+    fn default() -> Self {
+        Self {
+            // ST Microelectronics
+            vid: 0x0483,
+            // STM32 virtual COM port
+            pid: 0x5740,
+            manufacturer: "nankeen.me",
+            product: "STM32 serprog",
+            max_packet_size_0: 64,
+        }
+    }
+}
+
+impl UsbConfig {
+    /// Builds the CDC device, the serial number is per board so it is passed in separately
+    pub(crate) fn build<'a, B: UsbBus>(
+        &self,
+        usb_bus: &'a UsbBusAllocator<B>,
+        serial_number: &'a str,
+    ) -> UsbDevice<'a, B> {
+        UsbDeviceBuilder::new(usb_bus, UsbVidPid(self.vid, self.pid))
+            .manufacturer(self.manufacturer)
+            .product(self.product)
+            .serial_number(serial_number)
+            .device_class(USB_CLASS_CDC)
+            .max_packet_size_0(self.max_packet_size_0)
+            .build()
+    }
+}